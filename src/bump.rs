@@ -0,0 +1,69 @@
+use crate::{
+    material::tangent_frame,
+    math::{avg_elm, cross, dot, Vec3},
+    texture::Texture,
+};
+
+const DELTA_UV: f64 = 1. / 1024.; // step of the finite differences of height textures
+
+// perturbs the shading normal of an object, the geometric normal is kept for the ray offsets
+// and for the light leak test of the integrators
+pub enum ShadingMap<'a> {
+    Bump {
+        height: Texture<'a>, // averaged over the channels
+        scale: f64,          // displacement of height 1 in world units
+    },
+    Normal {
+        texture: Texture<'a>, // tangent space, rgb in [0, 1], green along -v as in OpenGL
+    },
+}
+
+#[allow(unused)]
+impl<'a> ShadingMap<'a> {
+    pub fn set_bump(height: Texture<'a>, scale: f64) -> Self {
+        ShadingMap::Bump { height, scale }
+    }
+
+    pub fn set_normal(texture: Texture<'a>) -> Self {
+        ShadingMap::Normal { texture }
+    }
+
+    pub fn perturb(&self, normal: &Vec3, dp: &(Vec3, Vec3), uv: (f64, f64)) -> Vec3 {
+        //return the shading normal on the side of normal, dp: (dp/du, dp/dv) at the hit
+        let (dpdu, dpdv) = dp;
+        let (u, v) = uv;
+        let shading = match self {
+            ShadingMap::Bump { height, scale } => {
+                // forward differences, backward at the upper edge of the texture
+                let h = |u: f64, v: f64| avg_elm(&height.get_color(u, v)) * scale;
+                let du = if u + DELTA_UV < 1. {
+                    DELTA_UV
+                } else {
+                    -DELTA_UV
+                };
+                let dv = if v + DELTA_UV < 1. {
+                    DELTA_UV
+                } else {
+                    -DELTA_UV
+                };
+                let h0 = h(u, v);
+                let dhdu = (h(u + du, v) - h0) / du;
+                let dhdv = (h(u, v + dv) - h0) / dv;
+                // dp/du x dp/dv can face either side of the geometric normal
+                let side = dot(cross(*dpdu, *dpdv), *normal).signum();
+                cross(*dpdu + *normal * dhdu, *dpdv + *normal * dhdv) * side
+            }
+            ShadingMap::Normal { texture } => {
+                let m = texture.get_color(u, v) * 2. - Vec3::new(1.);
+                let (t, b) = tangent_frame(normal, dpdu);
+                t * m.0 + b * m.1 + *normal * m.2
+            }
+        };
+        // perturbations beyond the horizon are not representable
+        if dot(shading, *normal) <= 0. {
+            *normal
+        } else {
+            shading.normalize()
+        }
+    }
+}
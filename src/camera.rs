@@ -9,9 +9,15 @@ pub trait Camara: Send + Sync {
     fn get_coeff(&self) -> f64;
     fn setup(&self, u: u32, v: u32, su: u32, sv: u32, rand: &mut XorRand) -> (f64, Vec3, Vec3);
     //return (g_term, org, dir)
+    fn sample_aperture(&self, rand: &mut XorRand) -> Point3;
+    fn world_to_raster(&self, pos: Point3, lens_pos: Point3) -> Option<(u32, u32)>;
+    //return (u, v) of the pixel seeing pos through lens_pos
+    fn importance(&self, pos: Point3, lens_pos: Point3) -> f64;
+    //return We * cos / (l^2 * pdf_aperture), the weight of a connection from pos to lens_pos
 }
 
-pub struct LensModel {
+// sensor and focus plane of a thin lens, LensModel and HexLensModel only differ in the aperture
+struct ThinLens {
     pixel_w: u32,
     pixel_h: u32,
     sensor_dir: Vec3,
@@ -23,7 +29,6 @@ pub struct LensModel {
     superpx_u: Vec3,
     superpx_v: Vec3,
     sensor_to_lens: f64,
-    lens_radius: f64,
     lens_center: Point3,
     lens_to_plane: f64,
     iso: f64,
@@ -31,14 +36,13 @@ pub struct LensModel {
     sspp: u32, //super samples per pixel
 }
 
-impl LensModel {
-    pub fn new(
+impl ThinLens {
+    fn new(
         px_w: u32,
         px_h: u32,
         sensor_dir: Vec3,
         sensor_center: Point3,
         sensor_w: f64,
-        lens_r: f64,
         focal_len: f64, //=sensor_to_lens
         lens_to_plane: f64,
         iso_scale: f64,
@@ -57,95 +61,160 @@ impl LensModel {
         let superpx_v = sensor_v * h_per_px / sspp as f64;
         let lens_center = sensor_center + sensor_dir * focal_len;
 
-        LensModel {
+        ThinLens {
             pixel_w: px_w,
             pixel_h: px_h,
-            sensor_dir: sensor_dir,
-            sensor_w: sensor_w,
-            sensor_h: sensor_h,
-            sensor_u: sensor_u,
-            sensor_v: sensor_v,
-            sensor_corner: sensor_corner,
-            superpx_u: superpx_u,
-            superpx_v: superpx_v,
+            sensor_dir,
+            sensor_w,
+            sensor_h,
+            sensor_u,
+            sensor_v,
+            sensor_corner,
+            superpx_u,
+            superpx_v,
             sensor_to_lens: focal_len,
-            lens_radius: lens_r,
-            lens_center: lens_center,
-            lens_to_plane: lens_to_plane,
-            iso: iso,
-            spp: spp,
-            sspp: sspp,
+            lens_center,
+            lens_to_plane,
+            iso,
+            spp,
+            sspp,
         }
     }
 
-    fn sample_lens(&self, pixel_pos: Point3, rand: &mut XorRand) -> (f64, Point3) {
-        //return (coefficient=cos^2/l^2, sample_pos)
-        let theta = 2.0 * PI * rand.next01();
-        let r = rand.next01().sqrt() * self.lens_radius;
-
-        let lens_pos =
-            self.lens_center + self.sensor_u * r * theta.cos() + self.sensor_v * r * theta.sin();
-        let l_sq = (lens_pos - pixel_pos).length_sq();
-        let cos_theta = dot((lens_pos - pixel_pos).normalize(), self.sensor_dir);
-        (cos_theta * cos_theta / l_sq, lens_pos)
-    }
-
     fn first_dir(&self, pixel_pos: Point3, lens_pos: Point3) -> Vec3 {
         let plane_pos = (self.lens_center - pixel_pos) * (self.sensor_to_lens + self.lens_to_plane)
             / self.sensor_to_lens
             + pixel_pos;
         (plane_pos - lens_pos).normalize()
     }
+
+    fn get_coeff(&self, lens_area: f64) -> f64 {
+        let w_per_px = self.sensor_w / self.pixel_w as f64;
+        let h_per_px = self.sensor_h / self.pixel_h as f64;
+        self.iso * w_per_px * h_per_px * lens_area / (self.spp as f64 * self.sspp.pow(2) as f64)
+    }
+
+    fn setup(&self, u: u32, v: u32, su: u32, sv: u32, lens_pos: Point3) -> (f64, Vec3, Vec3) {
+        //return (g_term=cos^2/l^2, org, dir) of the ray through lens_pos
+        let u = self.pixel_w - u - 1;
+        let v = self.pixel_h - v - 1;
+        let pixel_pos = self.sensor_corner
+            + self.superpx_u * ((u * self.sspp + su) as f64 + 0.5)
+            + self.superpx_v * ((v * self.sspp + sv) as f64 + 0.5);
+
+        let l_sq = (lens_pos - pixel_pos).length_sq();
+        let cos_theta = dot((lens_pos - pixel_pos).normalize(), self.sensor_dir);
+        let dir = self.first_dir(pixel_pos, lens_pos);
+
+        (cos_theta * cos_theta / l_sq, lens_pos, dir)
+    }
+
+    fn world_to_raster(&self, pos: Point3, lens_pos: Point3) -> Option<(u32, u32)> {
+        let dir = (pos - lens_pos).normalize();
+        let cos_theta = dot(dir, self.sensor_dir);
+        if cos_theta <= 0. {
+            return None;
+        }
+
+        let plane_pos = lens_pos + dir * self.lens_to_plane / cos_theta;
+        let sensor_pos = self.lens_center
+            - (plane_pos - self.lens_center) * self.sensor_to_lens / self.lens_to_plane;
+        let (u, v) = sensor_raster(
+            sensor_pos - self.sensor_corner,
+            self.sensor_u * self.pixel_w as f64 / self.sensor_w,
+            self.sensor_v * self.pixel_h as f64 / self.sensor_h,
+            self.pixel_w,
+            self.pixel_h,
+        )?;
+        Some((self.pixel_w - u - 1, self.pixel_h - v - 1))
+    }
+
+    fn importance(&self, pos: Point3, lens_pos: Point3, lens_area: f64) -> f64 {
+        let lens_to_pos = pos - lens_pos;
+        let cos_theta = dot(lens_to_pos.normalize(), self.sensor_dir);
+        self.iso * lens_area * cos_theta / lens_to_pos.length_sq()
+    }
+}
+
+pub struct LensModel {
+    lens: ThinLens,
+    lens_radius: f64,
+}
+
+impl LensModel {
+    pub fn new(
+        px_w: u32,
+        px_h: u32,
+        sensor_dir: Vec3,
+        sensor_center: Point3,
+        sensor_w: f64,
+        lens_r: f64,
+        focal_len: f64, //=sensor_to_lens
+        lens_to_plane: f64,
+        iso_scale: f64,
+        spp: u32,
+        sspp: u32,
+    ) -> Self {
+        LensModel {
+            lens: ThinLens::new(
+                px_w,
+                px_h,
+                sensor_dir,
+                sensor_center,
+                sensor_w,
+                focal_len,
+                lens_to_plane,
+                iso_scale,
+                spp,
+                sspp,
+            ),
+            lens_radius: lens_r,
+        }
+    }
+
+    fn lens_area(&self) -> f64 {
+        PI * self.lens_radius * self.lens_radius
+    }
 }
 
 impl Camara for LensModel {
     fn get_pixel(&self) -> (u32, u32) {
-        (self.pixel_w, self.pixel_h)
+        (self.lens.pixel_w, self.lens.pixel_h)
     }
 
     fn get_sample(&self) -> (u32, u32) {
-        (self.spp, self.sspp)
+        (self.lens.spp, self.lens.sspp)
     }
 
     fn get_coeff(&self) -> f64 {
-        let w_per_px = self.sensor_w / self.pixel_w as f64;
-        let h_per_px = self.sensor_h / self.pixel_h as f64;
-        let lens_area = PI * self.lens_radius * self.lens_radius;
-        self.iso * w_per_px * h_per_px * lens_area / (self.spp as f64 * self.sspp.pow(2) as f64)
+        self.lens.get_coeff(self.lens_area())
     }
 
     fn setup(&self, u: u32, v: u32, su: u32, sv: u32, rand: &mut XorRand) -> (f64, Vec3, Vec3) {
-        let u = self.pixel_w - u - 1;
-        let v = self.pixel_h - v - 1;
-        let pixel_pos = self.sensor_corner
-            + self.superpx_u * ((u * self.sspp + su) as f64 + 0.5)
-            + self.superpx_v * ((v * self.sspp + sv) as f64 + 0.5);
+        self.lens.setup(u, v, su, sv, self.sample_aperture(rand))
+    }
 
-        let (g_term, lens_pos) = self.sample_lens(pixel_pos, rand);
-        let dir = self.first_dir(pixel_pos, lens_pos);
+    fn sample_aperture(&self, rand: &mut XorRand) -> Point3 {
+        let theta = 2.0 * PI * rand.next01();
+        let r = rand.next01().sqrt() * self.lens_radius;
+
+        self.lens.lens_center
+            + self.lens.sensor_u * r * theta.cos()
+            + self.lens.sensor_v * r * theta.sin()
+    }
+
+    fn world_to_raster(&self, pos: Point3, lens_pos: Point3) -> Option<(u32, u32)> {
+        self.lens.world_to_raster(pos, lens_pos)
+    }
 
-        (g_term, lens_pos, dir)
+    fn importance(&self, pos: Point3, lens_pos: Point3) -> f64 {
+        self.lens.importance(pos, lens_pos, self.lens_area())
     }
 }
 
 pub struct HexLensModel {
-    pixel_w: u32,
-    pixel_h: u32,
-    sensor_dir: Vec3,
-    sensor_w: f64,
-    sensor_h: f64,
-    sensor_u: Vec3,
-    sensor_v: Vec3,
-    sensor_corner: Point3,
-    superpx_u: Vec3,
-    superpx_v: Vec3,
-    sensor_to_lens: f64,
+    lens: ThinLens,
     lens_side: f64,
-    lens_center: Point3,
-    lens_to_plane: f64,
-    iso: f64,
-    spp: u32,
-    sspp: u32,
 }
 
 #[allow(unused)]
@@ -163,103 +232,81 @@ impl HexLensModel {
         spp: u32,
         sspp: u32,
     ) -> Self {
-        let sensor_h = sensor_w * (px_h as f64 / px_w as f64);
-        let w_per_px = sensor_w / px_w as f64;
-        let h_per_px = sensor_h / px_h as f64;
-        let iso = iso_scale / (w_per_px * h_per_px);
-
-        let sensor_u = cross(sensor_dir, Vec3(0., 1., 0.)).normalize();
-        let sensor_v = cross(sensor_dir, sensor_u).normalize();
-        let sensor_corner = sensor_center - sensor_u * sensor_w / 2. - sensor_v * sensor_h / 2.;
-        let superpx_u = sensor_u * w_per_px / sspp as f64;
-        let superpx_v = sensor_v * h_per_px / sspp as f64;
-        let lens_center = sensor_center + sensor_dir * focal_len;
-
         HexLensModel {
-            pixel_w: px_w,
-            pixel_h: px_h,
-            sensor_dir,
-            sensor_w,
-            sensor_h,
-            sensor_u,
-            sensor_v,
-            sensor_corner,
-            superpx_u,
-            superpx_v,
-            sensor_to_lens: focal_len,
+            lens: ThinLens::new(
+                px_w,
+                px_h,
+                sensor_dir,
+                sensor_center,
+                sensor_w,
+                focal_len,
+                lens_to_plane,
+                iso_scale,
+                spp,
+                sspp,
+            ),
             lens_side,
-            lens_center,
-            lens_to_plane,
-            iso,
-            spp,
-            sspp,
-        }
-    }
-
-    fn sample_lens(&self, pixel_pos: Point3, rand: &mut XorRand) -> (f64, Point3) {
-        let idx_p = (rand.nexti() % 6) as f64;
-        let idx_q = if idx_p == 5. { 0. } else { idx_p + 1. };
-
-        let p = self.sensor_u * self.lens_side * (idx_p / 3. * PI).cos()
-            + self.sensor_v * self.lens_side * (idx_p / 3. * PI).sin();
-        let q = self.sensor_u * self.lens_side * (idx_q / 3. * PI).cos()
-            + self.sensor_v * self.lens_side * (idx_q / 3. * PI).sin();
-
-        let mut s = rand.next01();
-        let mut t = rand.next01();
-        if s + t > 1. {
-            s = 1. - s;
-            t = 1. - t;
         }
-
-        let lens_pos = self.lens_center + p * s + q * t;
-        let l_sq = (lens_pos - pixel_pos).length_sq();
-        let cos_theta = dot((lens_pos - pixel_pos).normalize(), self.sensor_dir);
-        (cos_theta * cos_theta / l_sq, lens_pos)
     }
 
-    fn first_dir(&self, pixel_pos: Point3, lens_pos: Point3) -> Vec3 {
-        let plane_pos = (self.lens_center - pixel_pos) * (self.sensor_to_lens + self.lens_to_plane)
-            / self.sensor_to_lens
-            + pixel_pos;
-        (plane_pos - lens_pos).normalize()
+    fn lens_area(&self) -> f64 {
+        (3_f64).powf(1.5) * self.lens_side * self.lens_side
     }
 }
 
 impl Camara for HexLensModel {
     fn get_pixel(&self) -> (u32, u32) {
-        (self.pixel_w, self.pixel_h)
+        (self.lens.pixel_w, self.lens.pixel_h)
     }
 
     fn get_sample(&self) -> (u32, u32) {
-        (self.spp, self.sspp)
+        (self.lens.spp, self.lens.sspp)
     }
 
     fn get_coeff(&self) -> f64 {
-        let w_per_px = self.sensor_w / self.pixel_w as f64;
-        let h_per_px = self.sensor_h / self.pixel_h as f64;
-        let lens_area = (3_f64).powf(1.5) * self.lens_side * self.lens_side;
-        self.iso * w_per_px * h_per_px * lens_area / (self.spp as f64 * self.sspp.pow(2) as f64)
+        self.lens.get_coeff(self.lens_area())
     }
 
     fn setup(&self, u: u32, v: u32, su: u32, sv: u32, rand: &mut XorRand) -> (f64, Vec3, Vec3) {
-        let u = self.pixel_w - u - 1;
-        let v = self.pixel_h - v - 1;
-        let pixel_pos = self.sensor_corner
-            + self.superpx_u * ((u * self.sspp + su) as f64 + 0.5)
-            + self.superpx_v * ((v * self.sspp + sv) as f64 + 0.5);
+        self.lens.setup(u, v, su, sv, self.sample_aperture(rand))
+    }
 
-        let (g_term, lens_pos) = self.sample_lens(pixel_pos, rand);
-        let dir = self.first_dir(pixel_pos, lens_pos);
+    fn sample_aperture(&self, rand: &mut XorRand) -> Point3 {
+        let idx_p = (rand.nexti() % 6) as f64;
+        let idx_q = if idx_p == 5. { 0. } else { idx_p + 1. };
+
+        let (sensor_u, sensor_v) = (self.lens.sensor_u, self.lens.sensor_v);
+        let p = sensor_u * self.lens_side * (idx_p / 3. * PI).cos()
+            + sensor_v * self.lens_side * (idx_p / 3. * PI).sin();
+        let q = sensor_u * self.lens_side * (idx_q / 3. * PI).cos()
+            + sensor_v * self.lens_side * (idx_q / 3. * PI).sin();
+
+        let mut s = rand.next01();
+        let mut t = rand.next01();
+        if s + t > 1. {
+            s = 1. - s;
+            t = 1. - t;
+        }
+
+        self.lens.lens_center + p * s + q * t
+    }
 
-        (g_term, lens_pos, dir)
+    fn world_to_raster(&self, pos: Point3, lens_pos: Point3) -> Option<(u32, u32)> {
+        self.lens.world_to_raster(pos, lens_pos)
+    }
+
+    fn importance(&self, pos: Point3, lens_pos: Point3) -> f64 {
+        self.lens.importance(pos, lens_pos, self.lens_area())
     }
 }
 
 pub struct PinholeModel {
     eye: Point3,
+    eye_dir: Vec3,
+    eye_to_sensor: f64,
     pixel_w: u32,
     pixel_h: u32,
+    px_area: f64,
     sensor_corner: Point3,
     superpx_u: Vec3,
     superpx_v: Vec3,
@@ -288,8 +335,11 @@ impl PinholeModel {
 
         PinholeModel {
             eye: eye_pos,
+            eye_dir,
+            eye_to_sensor,
             pixel_w: px_w,
             pixel_h: px_h,
+            px_area: sensor_w * sensor_h / (px_w * px_h) as f64,
             sensor_corner: sensor_corner,
             superpx_u: superpx_u,
             superpx_v: superpx_v,
@@ -320,4 +370,115 @@ impl Camara for PinholeModel {
 
         (1.0, self.eye, dir)
     }
+
+    fn sample_aperture(&self, _: &mut XorRand) -> Point3 {
+        self.eye
+    }
+
+    fn world_to_raster(&self, pos: Point3, _: Point3) -> Option<(u32, u32)> {
+        let dir = (pos - self.eye).normalize();
+        let cos_theta = dot(dir, self.eye_dir);
+        if cos_theta <= 0. {
+            return None;
+        }
+
+        let sensor_pos = self.eye + dir * self.eye_to_sensor / cos_theta;
+        sensor_raster(
+            sensor_pos - self.sensor_corner,
+            self.superpx_u / self.superpx_u.length_sq() / self.sspp as f64,
+            self.superpx_v / self.superpx_v.length_sq() / self.sspp as f64,
+            self.pixel_w,
+            self.pixel_h,
+        )
+    }
+
+    fn importance(&self, pos: Point3, _: Point3) -> f64 {
+        let eye_to_pos = pos - self.eye;
+        let cos_theta = dot(eye_to_pos.normalize(), self.eye_dir);
+        self.eye_to_sensor * self.eye_to_sensor
+            / (self.px_area * cos_theta.powi(3) * eye_to_pos.length_sq())
+    }
+}
+
+fn sensor_raster(
+    corner_to_pos: Vec3,
+    u_per_len: Vec3,
+    v_per_len: Vec3,
+    px_w: u32,
+    px_h: u32,
+) -> Option<(u32, u32)> {
+    //u_per_len, v_per_len: sensor axes scaled to pixels per unit length
+    let u = dot(corner_to_pos, u_per_len);
+    let v = dot(corner_to_pos, v_per_len);
+    if u < 0. || v < 0. || u >= px_w as f64 || v >= px_h as f64 {
+        return None;
+    }
+    Some((u as u32, v as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flux_ratio(camera: &impl Camara, plane_z: f64) -> f64 {
+        //return the image of a diffuse plane of unit radiance at z = plane_z, traced from the
+        //light over traced from the camera
+        let mut rand = XorRand::new(7);
+        let (pixel_w, pixel_h) = camera.get_pixel();
+        let (spp, sspp) = camera.get_sample();
+
+        let mut camera_flux = 0.;
+        for v in 0..pixel_h {
+            for u in 0..pixel_w {
+                for sv in 0..sspp {
+                    for su in 0..sspp {
+                        for _ in 0..spp {
+                            let (g_term, ..) = camera.setup(u, v, su, sv, &mut rand);
+                            camera_flux += g_term * camera.get_coeff();
+                        }
+                    }
+                }
+            }
+        }
+
+        // the plane covers the view, points on it are sampled uniformly
+        let side = 400.;
+        let paths = 400000;
+        let mut light_flux = 0.;
+        for _ in 0..paths {
+            let pos = Vec3(
+                (rand.next01() - 0.5) * side,
+                (rand.next01() - 0.5) * side,
+                plane_z,
+            );
+            let lens_pos = camera.sample_aperture(&mut rand);
+            if camera.world_to_raster(pos, lens_pos).is_some() {
+                let cos = dot(Vec3(0., 0., 1.), (lens_pos - pos).normalize());
+                light_flux += camera.importance(pos, lens_pos) * cos * side * side;
+            }
+        }
+
+        light_flux / paths as f64 / camera_flux
+    }
+
+    #[test]
+    fn pinhole_and_lens_agree_on_flux() {
+        let pinhole = PinholeModel::new(Vec3::new(0.), 16, 12, 30., Vec3(0., 0., -1.), 40., 4, 2);
+        let lens = LensModel::new(
+            16,
+            12,
+            Vec3(0., 0., -1.),
+            Vec3(0., 0., 42.),
+            30.,
+            2.,
+            42.,
+            96.,
+            100.,
+            4,
+            2,
+        );
+
+        assert!((flux_ratio(&pinhole, -100.) - 1.).abs() < 0.02);
+        assert!((flux_ratio(&lens, -96.) - 1.).abs() < 0.02);
+    }
 }
@@ -0,0 +1,138 @@
+use num_complex::Complex;
+
+use crate::{
+    material::reflection_dir,
+    math::{avg_elm, dot, Color, Vec3, PI},
+    random::XorRand,
+};
+
+// thin dielectric film on a surface, e.g. soap bubbles, oil slicks and anodized metal.
+// the reflectance is the Airy summation of the interreflections in the film, point sampled
+// at the wavelengths of the path. rgb mode samples it at LAMBDA_RGB instead of integrating
+// over the sensitivity of each channel, so films thicker than about a micron alias into
+// bands of color that spectral mode averages out
+#[derive(Clone, Copy)]
+pub struct Film {
+    pub thickness: f64, // [nm]
+    pub ior: f64,
+}
+
+#[allow(unused)]
+impl Film {
+    pub fn new(thickness: f64, ior: f64) -> Self {
+        Film { thickness, ior }
+    }
+
+    pub fn reflectance(
+        &self,
+        ior_i: f64,
+        cior: &Color,
+        k: &Color,
+        cos_i: f64,
+        lambdas: &Vec3,
+    ) -> Color {
+        //return reflectance from the medium of ior_i through the film onto the substrate of
+        //cior + i k, k = 0 for dielectrics
+        let cos_i = cos_i.abs().min(1.);
+        let cior = [cior.0, cior.1, cior.2];
+        let k = [k.0, k.1, k.2];
+        let lambdas = [lambdas.0, lambdas.1, lambdas.2];
+        let mut refl = [0.; 3];
+
+        let n1 = Complex::new(ior_i, 0.);
+        let n2 = Complex::new(self.ior, 0.);
+        let sin_i_sq = 1. - cos_i * cos_i;
+        let c1 = Complex::new(cos_i, 0.);
+        let c2 = cos_t(ior_i, n2, sin_i_sq);
+        let (r12s, r12p) = fresnel_amplitude(n1, c1, n2, c2);
+
+        for i in 0..3 {
+            let n3 = Complex::new(cior[i], k[i]);
+            let c3 = cos_t(ior_i, n3, sin_i_sq);
+            let (r23s, r23p) = fresnel_amplitude(n2, c2, n3, c3);
+
+            // phase difference of a round trip in the film
+            let delta = n2 * c2 * (4. * PI * self.thickness / lambdas[i]);
+            let phase = (Complex::<f64>::i() * delta).exp();
+            let airy = |r12: Complex<f64>, r23: Complex<f64>| {
+                ((r12 + r23 * phase) / (Complex::new(1., 0.) + r12 * r23 * phase)).norm_sqr()
+            };
+            refl[i] = ((airy(r12s, r23s) + airy(r12p, r23p)) / 2.).min(1.);
+        }
+        Vec3(refl[0], refl[1], refl[2])
+    }
+
+    pub fn fr_surface(
+        &self,
+        cior: &Color,
+        k: &Color,
+        f0: &Color,
+        wo: &Vec3,
+        vn: &Vec3,
+        lambdas: &Vec3,
+    ) -> Color {
+        //return reflectance of Specular and MicroBrdf, dielectrics (cior < 0) take the ior
+        //from the normal incidence reflectance f0
+        if cior.0 < 0. {
+            let ior = ior_from_f0(f0);
+            self.reflectance(1., &ior, &Vec3::new(0.), dot(*wo, *vn), lambdas)
+        } else {
+            self.reflectance(1., cior, k, dot(*wo, *vn), lambdas)
+        }
+    }
+}
+
+pub fn sample_film_dielectric(
+    film: &Film,
+    ior_i: f64,
+    ior_t: f64,
+    normal: &Vec3,
+    in_dir: &Vec3,
+    lambdas: &Vec3,
+    rand: &mut XorRand,
+) -> (bool, Vec3, Color, f64) {
+    //return (is_refract, new_dir, reflectance or transmittance, prob) of a dielectric
+    //surface with a film, the film does not change the refracted direction
+    let reflection_dir = reflection_dir(*normal, *in_dir);
+    let nnt = ior_i / ior_t;
+    let ddn = dot(*in_dir, *normal);
+    let cos2t = 1. - nnt * nnt * (1. - ddn * ddn);
+    if cos2t < 0. {
+        return (false, reflection_dir, Vec3::new(1.), 1.);
+    }
+
+    let refraction_dir = (-*normal * cos2t.sqrt() + (*in_dir - *normal * ddn) * nnt).normalize();
+    let refl = film.reflectance(ior_i, &Vec3::new(ior_t), &Vec3::new(0.), ddn, lambdas);
+    let prob = avg_elm(&refl);
+    if rand.next01() < prob {
+        (false, reflection_dir, refl, prob)
+    } else {
+        (true, refraction_dir, Vec3::new(1.) - refl, 1. - prob)
+    }
+}
+
+fn cos_t(ior_i: f64, ior_t: Complex<f64>, sin_i_sq: f64) -> Complex<f64> {
+    // complex beyond the critical angle, the wave in the film is evanescent
+    let sin_t_sq = Complex::new(ior_i * ior_i * sin_i_sq, 0.) / (ior_t * ior_t);
+    (Complex::new(1., 0.) - sin_t_sq).sqrt()
+}
+
+fn fresnel_amplitude(
+    n1: Complex<f64>,
+    c1: Complex<f64>,
+    n2: Complex<f64>,
+    c2: Complex<f64>,
+) -> (Complex<f64>, Complex<f64>) {
+    //return amplitude reflection coefficients (s, p)
+    let rs = (n1 * c1 - n2 * c2) / (n1 * c1 + n2 * c2);
+    let rp = (n2 * c1 - n1 * c2) / (n2 * c1 + n1 * c2);
+    (rs, rp)
+}
+
+fn ior_from_f0(f0: &Color) -> Color {
+    let ior = |f: f64| {
+        let s = f.clamp(0., 0.99).sqrt();
+        (1. + s) / (1. - s)
+    };
+    Vec3(ior(f0.0), ior(f0.1), ior(f0.2))
+}
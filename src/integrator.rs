@@ -0,0 +1,110 @@
+use crate::{
+    material::{sample_lambert, tangent_frame},
+    math::{dot, Color, Vec3},
+    object::Object,
+    pathtracing::{PathConfig, Pathtracing},
+    random::XorRand,
+    ray::{HitRecord, Ray},
+    scene::Scene,
+};
+
+#[allow(unused)]
+pub enum Integrator {
+    Pathtracing,
+    DirectLighting,
+    AmbientOcclusion { radius: f64 },
+    PathDepth { max_depth: u32 },
+    BvhCost { max_cost: u32 },
+    Normal,
+    Tangent,
+    Uv,
+    Barycentric,
+}
+
+impl Integrator {
+    pub fn is_radiometric(&self) -> bool {
+        // radiometric results are scaled by the camera, the others are plain averages
+        matches!(self, Integrator::Pathtracing | Integrator::DirectLighting)
+    }
+
+    pub fn trace(&self, scene: &Scene, ray: Ray, config: &PathConfig, rand: &mut XorRand) -> Color {
+        match self {
+            Integrator::Pathtracing => Pathtracing::new(ray, *config).integrate(scene, rand),
+            Integrator::DirectLighting => {
+                // the emission found by the first scattering event is still counted
+                let config = PathConfig {
+                    max_depth: 1,
+                    ..*config
+                };
+                Pathtracing::new(ray, config).integrate(scene, rand)
+            }
+            Integrator::AmbientOcclusion { radius } => {
+                ambient_occlusion(scene, &ray, *radius, rand)
+            }
+            Integrator::PathDepth { max_depth } => {
+                let mut tracer = Pathtracing::new(ray, *config);
+                let _ = tracer.integrate(scene, rand);
+                false_color(tracer.get_depth() as f64 / *max_depth as f64)
+            }
+            Integrator::BvhCost { max_cost } => {
+                let cost = scene.traversal_cost(&ray, &mut HitRecord::new(), &scene.bvh_tree[0]);
+                false_color(cost as f64 / *max_cost as f64)
+            }
+            Integrator::Normal | Integrator::Tangent | Integrator::Uv | Integrator::Barycentric => {
+                let mut record = HitRecord::new();
+                if !scene.intersect_obj(&ray, &mut record, &scene.bvh_tree[0]) {
+                    return Vec3::new(0.);
+                }
+
+                let (u, v) = record.uv;
+                match self {
+                    // shading normals, with the bump and normal maps
+                    Integrator::Normal => record.shading_normal * 0.5 + Vec3::new(0.5),
+                    Integrator::Tangent => {
+                        tangent_frame(&record.shading_normal, &record.tangent).0 * 0.5
+                            + Vec3::new(0.5)
+                    }
+                    Integrator::Uv => Vec3(u, v, 0.),
+                    _ => match scene.objects[record.obj_id as usize] {
+                        Object::Triangle { .. } => Vec3(1. - u - v, u, v),
+                        _ => Vec3::new(0.),
+                    },
+                }
+            }
+        }
+    }
+}
+
+fn ambient_occlusion(scene: &Scene, ray: &Ray, radius: f64, rand: &mut XorRand) -> Color {
+    let mut record = HitRecord::new();
+    if !scene.intersect_obj(ray, &mut record, &scene.bvh_tree[0]) {
+        return Vec3::new(0.);
+    }
+
+    let normal = if dot(record.normal, ray.dir) < 0. {
+        record.normal
+    } else {
+        -record.normal
+    };
+    let occlusion_ray = Ray {
+        org: record.pos + normal * 0.00001,
+        dir: sample_lambert(&normal, rand),
+    };
+
+    let mut occluder = HitRecord::init_with_dist(radius);
+    if scene.intersect_obj(&occlusion_ray, &mut occluder, &scene.bvh_tree[0]) {
+        Vec3::new(0.)
+    } else {
+        Vec3::new(1.)
+    }
+}
+
+fn false_color(t: f64) -> Color {
+    // blue -> cyan -> green -> yellow -> red
+    let t = t.clamp(0., 1.) * 4.;
+    Vec3(
+        (t - 2.).clamp(0., 1.),
+        t.min(4. - t).clamp(0., 1.),
+        (2. - t).clamp(0., 1.),
+    )
+}
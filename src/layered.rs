@@ -0,0 +1,170 @@
+use crate::{
+    material::{
+        fr_dielectric_ior, ggx_normal_df, reflection_dir, sample_ggx_vndf, shadow_mask_fn, Brdf,
+        Lobe, NO_TANGENT,
+    },
+    math::{dot, exp_vec, multiply, Color, Vec3},
+    random::XorRand,
+};
+
+// rough dielectric coat over a base bxdf, the base sees the directions refracted by the
+// flat interface. light reflected back by the coat from inside is accounted for with the
+// hemispherical internal reflectance for diffuse bases, glossy bases lose the light totally
+// reflected inside. Weidlich and Wilkie, "Arbitrarily Layered Micro-Facet Surfaces", 2007
+#[derive(Clone, Copy)]
+pub struct Coat {
+    pub a: f64,
+    pub ior: f64,
+    pub thickness: f64,
+    pub sigma_a: Color, // absorption of the coat, tints the base
+}
+
+#[allow(unused)]
+impl Coat {
+    pub fn new(a: f64, ior: f64) -> Self {
+        Coat {
+            a,
+            ior,
+            thickness: 0.,
+            sigma_a: Vec3::new(0.),
+        }
+    }
+
+    pub fn set_absorption(mut self, sigma_a: Color, thickness: f64) -> Self {
+        self.sigma_a = sigma_a;
+        self.thickness = thickness;
+        self
+    }
+
+    fn refract_in(&self, w: &Vec3, normal: &Vec3) -> (Vec3, f64) {
+        //return (direction in the coat on the same side, cos)
+        let cos = dot(*w, *normal);
+        let cos_t = (1. - (1. - cos * cos) / (self.ior * self.ior)).sqrt();
+        ((*w - *normal * cos) / self.ior + *normal * cos_t, cos_t)
+    }
+
+    fn refract_out(&self, w: &Vec3, normal: &Vec3) -> Option<Vec3> {
+        let cos = dot(*w, *normal);
+        let cos_t_sq = 1. - (1. - cos * cos) * self.ior * self.ior;
+        (cos > 0. && cos_t_sq > 0.)
+            .then(|| ((*w - *normal * cos) * self.ior + *normal * cos_t_sq.sqrt()).normalize())
+    }
+
+    fn alpha(&self) -> f64 {
+        self.a.max(0.001)
+    }
+
+    fn fresnel(&self, w: &Vec3, vn: &Vec3) -> f64 {
+        fr_dielectric_ior(true, 1., self.ior, w, vn)
+    }
+
+    fn internal_reflectance(&self, lobe: Option<Lobe>) -> f64 {
+        //return the fraction of light reflected back by the coat from inside, hemispherical
+        //for diffuse bases (Egan and Hilgeman), normal incidence for glossy ones
+        let ior = self.ior;
+        match lobe {
+            Some(Lobe::Diffuse) => -1.440 / (ior * ior) + 0.710 / ior + 0.668 + 0.0636 * ior,
+            _ => ((ior - 1.) / (ior + 1.)).powi(2),
+        }
+    }
+
+    fn coat_prob(&self, wi: &Vec3, normal: &Vec3) -> f64 {
+        self.fresnel(wi, normal).clamp(0.1, 0.9)
+    }
+
+    fn eval(
+        &self,
+        base: &impl Brdf,
+        lobe: Option<Lobe>,
+        color: &Color,
+        wi: &Vec3,
+        wo: &Vec3,
+        normal: &Vec3,
+    ) -> Color {
+        let cos_i = dot(*wi, *normal);
+        let cos_o = dot(*wo, *normal);
+        if cos_i <= 0. || cos_o <= 0. {
+            return Vec3::new(0.);
+        }
+
+        let vn = (*wi + *wo).normalize();
+        let a = self.alpha();
+        let alpha_sq = a * a;
+        let d = ggx_normal_df(a, a, normal, &NO_TANGENT, &vn);
+        let g = shadow_mask_fn(alpha_sq, wi, normal) * shadow_mask_fn(alpha_sq, wo, normal);
+        let coat = self.fresnel(wo, &vn) * d * g / (4. * cos_i * cos_o);
+
+        let (wi_t, cos_it) = self.refract_in(wi, normal);
+        let (wo_t, cos_ot) = self.refract_in(wo, normal);
+        let transmittance = exp_vec(self.sigma_a * -(self.thickness * (1. / cos_it + 1. / cos_ot)));
+        let albedo = multiply(*color, exp_vec(self.sigma_a * (-2. * self.thickness)));
+        let r_int = self.internal_reflectance(lobe);
+        let scale = (1. - self.fresnel(wi, normal)) * (1. - self.fresnel(wo, normal))
+            / (self.ior * self.ior);
+        let base = multiply(base.eval(color, &wi_t, &wo_t, normal), transmittance) * scale;
+
+        Vec3::new(coat)
+            + Vec3(
+                base.0 / (1. - albedo.0 * r_int),
+                base.1 / (1. - albedo.1 * r_int),
+                base.2 / (1. - albedo.2 * r_int),
+            )
+    }
+
+    fn sample(&self, base: &impl Brdf, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3 {
+        //return the sampled direction, below the surface when it is totally reflected inside
+        if rand.next01() < self.coat_prob(wi, normal) {
+            let a = self.alpha();
+            let vn = sample_ggx_vndf(normal, &NO_TANGENT, wi, a, a, rand);
+            return reflection_dir(vn, -*wi);
+        }
+
+        let (wi_t, _) = self.refract_in(wi, normal);
+        let wo_t = base.sample(&wi_t, normal, rand);
+        self.refract_out(&wo_t, normal).unwrap_or(-*normal)
+    }
+
+    fn pdf(&self, base: &impl Brdf, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        let cos_i = dot(*wi, *normal);
+        let cos_o = dot(*wo, *normal);
+        if cos_i <= 0. || cos_o <= 0. {
+            return 0.;
+        }
+
+        let vn = (*wi + *wo).normalize();
+        let a = self.alpha();
+        let alpha_sq = a * a;
+        let coat = shadow_mask_fn(alpha_sq, wi, normal)
+            * ggx_normal_df(a, a, normal, &NO_TANGENT, &vn)
+            / (4. * cos_i);
+
+        // solid angle of the refracted direction shrinks by cos_o / (ior^2 * cos_ot)
+        let (wi_t, _) = self.refract_in(wi, normal);
+        let (wo_t, cos_ot) = self.refract_in(wo, normal);
+        let base = base.pdf(&wi_t, &wo_t, normal) * cos_o / (self.ior * self.ior * cos_ot);
+
+        let prob = self.coat_prob(wi, normal);
+        prob * coat + (1. - prob) * base
+    }
+}
+
+// coat with its base resolved from Scene::materials, conductor bases are immersed in the coat
+pub struct Layered<B> {
+    pub coat: Coat,
+    pub base: B,
+    pub lobe: Option<Lobe>, // lobe of the base
+}
+
+impl<B: Brdf> Brdf for Layered<B> {
+    fn eval(&self, color: &Color, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color {
+        self.coat.eval(&self.base, self.lobe, color, wi, wo, normal)
+    }
+
+    fn sample(&self, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3 {
+        self.coat.sample(&self.base, wi, normal, rand)
+    }
+
+    fn pdf(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        self.coat.pdf(&self.base, wi, wo, normal)
+    }
+}
@@ -0,0 +1,633 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::{
+    camera::Camara,
+    material::*,
+    math::{dot, is_valid, multiply, Color, Point3, Vec3, PI},
+    medium::Interior,
+    multiscatter::{eval_ms_conductor, ggx_albedo},
+    pathtracing::{PathConfig, PathState},
+    random::XorRand,
+    ray::{HitRecord, Ray},
+    scene::Scene,
+    spectrum::{sample_wavelengths, spectrum_to_rgb},
+};
+
+// particle tracing from Scene::lights, every vertex is connected to the camera lens.
+// the background is not emitted, and paths reaching the lens only through specular
+// vertices can not be connected, so those are missing compared to Pathtracing.
+pub struct LightTracing {
+    state: PathState,
+}
+
+impl Deref for LightTracing {
+    type Target = PathState;
+
+    fn deref(&self) -> &PathState {
+        &self.state
+    }
+}
+
+impl DerefMut for LightTracing {
+    fn deref_mut(&mut self) -> &mut PathState {
+        &mut self.state
+    }
+}
+
+impl LightTracing {
+    pub fn new(config: PathConfig) -> Self {
+        let ray = Ray {
+            org: Vec3::new(0.),
+            dir: Vec3::new(0.),
+        };
+        LightTracing {
+            state: PathState::new(ray, Vec3::new(0.), config),
+        }
+    }
+
+    fn connect(
+        &self,
+        scene: &Scene,
+        camera: &impl Camara,
+        pos: Point3,
+        normal: Vec3,
+        rand: &mut XorRand,
+    ) -> Option<(usize, Vec3, Color)> {
+        //return (pixel index, direction to lens, importance * transmittance)
+        let lens_pos = camera.sample_aperture(rand);
+        let (u, v) = camera.world_to_raster(pos, lens_pos)?;
+
+        let to_lens = lens_pos - pos;
+        let dist = to_lens.length();
+        let dir = to_lens / dist;
+        let side = if dot(normal, dir) < 0. { -1. } else { 1. };
+        let ray = Ray {
+            org: pos + normal * side * 0.00001,
+            dir,
+        };
+
+        let mut record = HitRecord::init_with_dist(dist);
+        if scene.intersect_obj(&ray, &mut record, &scene.bvh_tree[0]) {
+            return None;
+        }
+        let transmittance =
+            scene.calc_transmittance(&ray, &self.medium_stack, dist, self.lambdas, rand);
+
+        let (pixel_w, _) = camera.get_pixel();
+        Some((
+            (v * pixel_w + u) as usize,
+            dir,
+            transmittance * camera.importance(pos, lens_pos),
+        ))
+    }
+
+    fn splat(&self, buffer: &mut [Color], idx: usize, contrib: Color) {
+        let contrib = match self.lambdas {
+            Some(lambdas) => spectrum_to_rgb(&contrib, &lambdas),
+            None => contrib,
+        } / self.roulette_pdf;
+        if is_valid(&contrib) {
+            buffer[idx] = buffer[idx] + contrib;
+        }
+    }
+
+    fn shading_correction(&self, wi: &Vec3, wo: &Vec3) -> f64 {
+        //return the factor making bxdfs with shading normals adjoint, wi: toward the light,
+        //wo: toward the camera. Veach, "Robust Monte Carlo Methods for Light Transport
+        //Simulation", 1997, 5.3
+        let denom = dot(*wi, self.geometric_normal) * dot(*wo, self.orienting_normal);
+        if denom == 0. {
+            return 0.;
+        }
+        (dot(*wi, self.orienting_normal) * dot(*wo, self.geometric_normal) / denom).abs()
+    }
+
+    fn ray_intersect(&mut self, scene: &Scene) -> bool {
+        self.record = HitRecord::new();
+        scene.intersect(
+            &self.state.now_ray,
+            &mut self.state.record,
+            &scene.bvh_tree[0],
+        )
+    }
+
+    fn eval_bxdf(&self, scene: &Scene, wo: &Vec3) -> Color {
+        //return bxdf * cos_o, light comes from -now_ray.dir
+        let wi = -self.now_ray.dir;
+        let normal = self.orienting_normal;
+        let cos_o = dot(normal, *wo);
+
+        match self.record.bxdf {
+            Bxdf::Lambertian => {
+                if cos_o > 0. {
+                    self.record.color * cos_o / PI
+                } else {
+                    Vec3::new(0.)
+                }
+            }
+            Bxdf::Translucent { transmission } => {
+                let weight = if cos_o > 0. {
+                    1. - transmission
+                } else {
+                    transmission
+                };
+                self.record.color * weight * cos_o.abs() / PI
+            }
+            Bxdf::OrenNayar { sigma } => {
+                self.record.color * eval_oren_nayar(sigma, &wi, wo, &normal) * cos_o
+            }
+            Bxdf::Principled {
+                params,
+                ior,
+                trans_id,
+            } => {
+                let tw = params.transmission_weight();
+                let into = self.medium_stack.is_into(trans_id);
+                let btdf = if tw > 0. {
+                    let ior = self.select_ior(&ior);
+                    let ior_out = self.medium_stack.outer_ior(trans_id);
+                    let (ior_i, ior_o) = if into { (ior_out, ior) } else { (ior, ior_out) };
+                    let a = params.alpha();
+                    let compensation =
+                        self.btdf_compensation(a, a, ior / ior_out, into == (cos_o > 0.), wo);
+                    self.record.color
+                        * eval_microbtdf(a, a, (ior_i, ior_o), &wi, wo, &normal, &NO_TANGENT)
+                        * cos_o.abs()
+                        * compensation
+                } else {
+                    Vec3::new(0.)
+                };
+                if tw > 0. && !into {
+                    btdf
+                } else {
+                    btdf * tw
+                        + params.eval(&self.record.color, &wi, wo, &normal) * cos_o * (1. - tw)
+                }
+            }
+            Bxdf::Coated { coat, base } => {
+                self.layers(scene)
+                    .layered(&coat, base)
+                    .eval(&self.record.color, &wi, wo, &normal)
+                    * cos_o
+            }
+            Bxdf::Measured { brdf } => {
+                self.layers(scene)
+                    .measured(brdf)
+                    .eval(&self.record.color, &wi, wo, &normal)
+                    * cos_o
+            }
+            Bxdf::Sheen { sheen, base } => {
+                self.layers(scene).sheen_layer(&sheen, base).eval(
+                    &self.record.color,
+                    &wi,
+                    wo,
+                    &normal,
+                ) * cos_o
+            }
+            Bxdf::MicroBrdf {
+                ax,
+                ay,
+                cior,
+                k,
+                metal,
+                ..
+            } => {
+                let (cior, k) = self.conductor_ior(&cior, &k, metal);
+                let (brdf, vn) = eval_microbrdf(ax, ay, &wi, wo, &normal, &self.record.tangent);
+                if brdf <= 0. {
+                    return Vec3::new(0.);
+                }
+                let ms = if self.config.multiscatter {
+                    let f0 = self.fresnel(&cior, &k, &normal, &normal);
+                    eval_ms_conductor((ax * ay).sqrt(), &f0, dot(wi, normal), cos_o)
+                } else {
+                    Vec3::new(0.)
+                };
+                (self.fresnel(&cior, &k, wo, &vn) * brdf + ms) * cos_o
+            }
+            Bxdf::MicroBtdf {
+                ax,
+                ay,
+                ior,
+                trans_id,
+            } => {
+                let ior = self.select_ior(&ior);
+                let ior_out = self.medium_stack.outer_ior(trans_id);
+                let into = self.medium_stack.is_into(trans_id);
+                let iors = if into { (ior_out, ior) } else { (ior, ior_out) };
+                let tangent = self.record.tangent;
+                // wo is on the other side when transmitted
+                let compensation =
+                    self.btdf_compensation(ax, ay, ior / ior_out, into == (cos_o > 0.), wo);
+                self.record.color
+                    * eval_microbtdf(ax, ay, iors, &wi, wo, &normal, &tangent)
+                    * cos_o.abs()
+                    * compensation
+            }
+            _ => Vec3::new(0.),
+        }
+    }
+
+    fn connect_surface(
+        &self,
+        scene: &Scene,
+        camera: &impl Camara,
+        rand: &mut XorRand,
+        buffer: &mut [Color],
+    ) {
+        let pos = self.record.pos;
+        if let Some((idx, dir, we)) = self.connect(scene, camera, pos, self.geometric_normal, rand)
+        {
+            if self.leaks(&dir) {
+                return;
+            }
+            let bxdf =
+                self.eval_bxdf(scene, &dir) * self.shading_correction(&-self.now_ray.dir, &dir);
+            let contrib = multiply(multiply(self.throughput, bxdf), we);
+            self.splat(buffer, idx, contrib);
+        }
+    }
+
+    fn trace_lambertian(&mut self, rand: &mut XorRand) {
+        let dir = sample_lambert(&self.orienting_normal, rand);
+        self.now_ray = Ray {
+            org: self.record.pos + self.orienting_normal * 0.00001,
+            dir,
+        };
+        self.throughput = multiply(self.throughput, self.record.color);
+    }
+
+    fn trace_translucent(&mut self, rand: &mut XorRand, transmission: f64) {
+        let side = if rand.next01() < transmission {
+            -self.orienting_normal
+        } else {
+            self.orienting_normal
+        };
+        self.now_ray = Ray {
+            org: self.record.pos + side * 0.00001,
+            dir: sample_lambert(&side, rand),
+        };
+        self.throughput = multiply(self.throughput, self.record.color);
+    }
+
+    fn trace_oren_nayar(&mut self, rand: &mut XorRand, sigma: f64) {
+        let wi = -self.now_ray.dir;
+        let dir = sample_lambert(&self.orienting_normal, rand);
+        self.now_ray = Ray {
+            org: self.record.pos + self.orienting_normal * 0.00001,
+            dir,
+        };
+        let brdf = eval_oren_nayar(sigma, &wi, &dir, &self.orienting_normal);
+        self.throughput = multiply(self.throughput, self.record.color) * brdf * PI;
+    }
+
+    fn trace_brdf(&mut self, rand: &mut XorRand, brdf: &impl Brdf) {
+        let wi = -self.now_ray.dir;
+        let dir = brdf.sample(&wi, &self.orienting_normal, rand);
+        self.now_ray = Ray {
+            org: self.record.pos + self.orienting_normal * 0.00001,
+            dir,
+        };
+
+        let pdf = brdf.pdf(&wi, &dir, &self.orienting_normal);
+        if pdf > 0. {
+            let f = brdf.eval(&self.record.color, &wi, &dir, &self.orienting_normal);
+            let cos_o = dot(self.orienting_normal, dir);
+            self.throughput = multiply(self.throughput, f) * cos_o / pdf;
+        } else {
+            self.throughput = Vec3::new(0.);
+        }
+    }
+
+    fn trace_specular(&mut self, cior: &Color, k: &Color) {
+        let out_dir = reflection_dir(self.orienting_normal, self.now_ray.dir);
+        self.now_ray = Ray {
+            org: self.record.pos + self.orienting_normal * 0.00001,
+            dir: out_dir,
+        };
+
+        let fresnel = self.fresnel(cior, k, &out_dir, &self.orienting_normal);
+        self.throughput = multiply(self.throughput, fresnel);
+    }
+
+    fn trace_dielectric(&mut self, interior: Interior, rand: &mut XorRand) {
+        let ior_out = self.medium_stack.outer_ior(interior.trans_id);
+        let into = self.medium_stack.is_into(interior.trans_id);
+
+        let (is_refract, out_dir, fresnel, prob) =
+            self.sample_dielectric(into, ior_out, interior.ior.unwrap(), rand);
+
+        let new_org = if is_refract {
+            self.medium_stack.cross(interior);
+            self.record.pos - self.orienting_normal * 0.00001
+        } else {
+            self.record.pos + self.orienting_normal * 0.00001
+        };
+
+        self.now_ray = Ray {
+            org: new_org,
+            dir: out_dir,
+        };
+
+        // importance is not scaled by the relative ior, unlike radiance
+        self.throughput = multiply(multiply(self.throughput, self.record.color), fresnel) / prob;
+    }
+
+    fn trace_thin_dielectric(&mut self, rand: &mut XorRand, ior: f64) {
+        let normal = self.orienting_normal;
+        let refl = fr_thin_dielectric(ior, &-self.now_ray.dir, &normal);
+        self.now_ray = if rand.next01() < refl {
+            Ray {
+                org: self.record.pos + normal * 0.00001,
+                dir: reflection_dir(normal, self.now_ray.dir),
+            }
+        } else {
+            Ray {
+                org: self.record.pos - normal * 0.00001,
+                dir: self.now_ray.dir,
+            }
+        };
+        self.throughput = multiply(self.throughput, self.record.color);
+    }
+
+    fn trace_microbrdf(&mut self, rand: &mut XorRand, ax: f64, ay: f64, cior: &Color, k: &Color) {
+        let wi = -self.now_ray.dir;
+        let normal = self.orienting_normal;
+        let tangent = self.record.tangent;
+        let alpha = (ax * ay).sqrt();
+        let cos_i = dot(wi, normal).abs();
+        // the multiple scattering lobe is sampled by cosine in proportion to its energy
+        let ms_prob = if self.config.multiscatter && alpha > 0. {
+            1. - ggx_albedo(alpha, cos_i)
+        } else {
+            0.
+        };
+
+        let dir = if rand.next01() < ms_prob {
+            sample_lambert(&normal, rand)
+        } else {
+            reflection_dir(sample_ggx_vndf(&normal, &tangent, &wi, ax, ay, rand), -wi)
+        };
+        let vn = (wi + dir).normalize();
+        let g1 = |w: &Vec3| shadow_mask_fn(ggx_alpha2(ax, ay, w, &normal, &tangent), w, &normal);
+        let fresnel = self.fresnel(cior, k, &dir, &vn);
+        let cos_o = dot(dir, normal);
+        let weight = if ms_prob == 0. {
+            fresnel * g1(&dir)
+        } else if cos_o <= 0. {
+            // lost below the surface, as in the albedo table
+            Vec3::new(0.)
+        } else {
+            let vndf = g1(&wi) * ggx_normal_df(ax, ay, &normal, &tangent, &vn) / (4. * cos_i);
+            let pdf = (1. - ms_prob) * vndf + ms_prob * cos_o / PI;
+            let f0 = self.fresnel(cior, k, &normal, &normal);
+            (fresnel * g1(&dir) * vndf + eval_ms_conductor(alpha, &f0, cos_i, cos_o) * cos_o) / pdf
+        };
+
+        self.now_ray = Ray {
+            org: self.record.pos + normal * 0.00001,
+            dir,
+        };
+        self.throughput = multiply(self.throughput, weight);
+    }
+
+    fn trace_microbtdf(&mut self, rand: &mut XorRand, ax: f64, ay: f64, interior: Interior) {
+        let wi = -self.now_ray.dir;
+        let normal = self.orienting_normal;
+        let tangent = self.record.tangent;
+        let ior_out = self.medium_stack.outer_ior(interior.trans_id);
+        let into = self.medium_stack.is_into(interior.trans_id);
+        let vn = sample_ggx_vndf(&normal, &tangent, &wi, ax, ay, rand);
+        let (is_refract, dir, ..) = refraction_dir(
+            into,
+            ior_out,
+            interior.ior.unwrap(),
+            vn,
+            self.now_ray.dir,
+            rand,
+        );
+        let g1_wo = shadow_mask_fn(ggx_alpha2(ax, ay, &dir, &normal, &tangent), &dir, &normal);
+        // dir is the camera side, inside after a refraction
+        let eta = interior.ior.unwrap() / ior_out;
+        let compensation = self.btdf_compensation(ax, ay, eta, into != is_refract, &dir);
+        // samples on the wrong side are lost, as in the albedo table
+        let kept = if self.config.multiscatter && (dot(dir, normal) < 0.) != is_refract {
+            0.
+        } else {
+            compensation
+        };
+
+        let org = if is_refract {
+            self.medium_stack.cross(interior);
+            self.record.pos - normal * 0.00001
+        } else {
+            self.record.pos + normal * 0.00001
+        };
+
+        self.now_ray = Ray { org, dir };
+        self.throughput = multiply(self.throughput, self.record.color) * g1_wo * kept;
+    }
+
+    fn freepath_sample(
+        &mut self,
+        scene: &Scene,
+        camera: &impl Camara,
+        rand: &mut XorRand,
+        buffer: &mut [Color],
+    ) -> Option<bool> {
+        //return Some(true) when a surface is hit, None when the path ends in the medium
+        let medium = self.medium_stack.get_medium().unwrap();
+        // emissive media are not light sources of the light tracer
+        let (dist, weight, _) =
+            scene.sample_free_path(&self.state.now_ray, &medium, &mut self.state.record, rand);
+        self.throughput = multiply(self.throughput, weight);
+
+        if let Some(dist) = dist {
+            if !self.next_bounce(Lobe::Volume) {
+                return None;
+            }
+
+            let pos = self.now_ray.org + self.now_ray.dir * dist;
+            self.geometric_normal = Vec3::new(0.);
+
+            if let Some((idx, dir, we)) = self.connect(scene, camera, pos, Vec3::new(0.), rand) {
+                let phase = medium.get_phase().pdf(&self.now_ray.dir, &dir);
+                self.splat(buffer, idx, multiply(self.throughput, we) * phase);
+            }
+
+            let dir = medium.get_phase().sample(&self.now_ray.dir, rand);
+            self.now_ray = Ray { org: pos, dir };
+            return Some(false);
+        }
+        (self.record.obj_id != -1).then_some(true)
+    }
+
+    pub fn trace(
+        &mut self,
+        scene: &Scene,
+        camera: &impl Camara,
+        rand: &mut XorRand,
+        buffer: &mut [Color],
+    ) {
+        if self.config.spectral {
+            self.lambdas = Some(sample_wavelengths(rand));
+        }
+        let Some((pos, normal, emission, pdf)) = scene.sample_emission(rand) else {
+            return;
+        };
+        let emission = self.to_spectrum(&emission);
+
+        if let Some((idx, dir, we)) = self.connect(scene, camera, pos, normal, rand) {
+            self.splat(
+                buffer,
+                idx,
+                multiply(emission, we) * dot(normal, dir).abs() / pdf,
+            );
+        }
+
+        // lights emit on both sides, pick one and sample cosine weighted
+        let side = if rand.next01() < 0.5 { normal } else { -normal };
+        self.now_ray = Ray {
+            org: pos + side * 0.00001,
+            dir: sample_lambert(&side, rand),
+        };
+        self.throughput = emission * 2. * PI / pdf;
+
+        loop {
+            if self.medium_stack.has_medium() {
+                let Some(hit_surface) = self.freepath_sample(scene, camera, rand, buffer) else {
+                    break;
+                };
+                if !hit_surface {
+                    let roulette_prob = self.roulette();
+                    if rand.next01() > roulette_prob {
+                        break;
+                    }
+                    self.roulette_pdf *= roulette_prob;
+                    continue;
+                }
+            } else if !self.ray_intersect(scene) {
+                break;
+            }
+            scene.select_mix(&mut self.record, rand);
+            self.record.color = self.to_spectrum(&self.record.color);
+            self.orient_normals();
+            if self.cross_interface(scene) {
+                continue;
+            }
+            if self.leaks(&self.now_ray.dir) {
+                break;
+            }
+
+            let roulette_prob = self.roulette();
+            if rand.next01() > roulette_prob {
+                break;
+            }
+            self.roulette_pdf *= roulette_prob;
+
+            let lobe = self.select_lobe(rand);
+            if let Some(lobe) = lobe {
+                if !self.next_bounce(lobe) {
+                    break;
+                }
+            }
+            if self.record.bxdf.is_dispersive() {
+                self.collapse_wavelengths();
+            }
+
+            match self.record.bxdf {
+                Bxdf::Lambertian
+                | Bxdf::Translucent { .. }
+                | Bxdf::OrenNayar { .. }
+                | Bxdf::Principled { .. }
+                | Bxdf::Coated { .. }
+                | Bxdf::Sheen { .. }
+                | Bxdf::Measured { .. }
+                | Bxdf::MicroBrdf { .. }
+                | Bxdf::MicroBtdf { .. } => {
+                    self.connect_surface(scene, camera, rand, buffer);
+                }
+                _ => {}
+            }
+
+            let wi = -self.now_ray.dir;
+            match self.record.bxdf {
+                Bxdf::Light => break,
+                Bxdf::Lambertian => {
+                    self.trace_lambertian(rand);
+                }
+                Bxdf::Translucent { transmission } => {
+                    self.trace_translucent(rand, transmission);
+                }
+                Bxdf::ThinDielectric { ior } => {
+                    let ior = self.select_ior(&ior);
+                    self.trace_thin_dielectric(rand, ior);
+                }
+                Bxdf::OrenNayar { sigma } => {
+                    self.trace_oren_nayar(rand, sigma);
+                }
+                Bxdf::Principled {
+                    params,
+                    ior,
+                    trans_id,
+                } => {
+                    if matches!(lobe, Some(Lobe::Transmission)) {
+                        let interior = self.interior(scene, trans_id, &ior);
+                        let a = params.alpha();
+                        self.trace_microbtdf(rand, a, a, interior);
+                    } else {
+                        self.trace_brdf(rand, &params);
+                    }
+                }
+                Bxdf::Coated { coat, base } => {
+                    let layered = self.layers(scene).layered(&coat, base);
+                    self.trace_brdf(rand, &layered);
+                }
+                Bxdf::Sheen { sheen, base } => {
+                    let sheen_layer = self.layers(scene).sheen_layer(&sheen, base);
+                    self.trace_brdf(rand, &sheen_layer);
+                }
+                Bxdf::Measured { brdf } => {
+                    let measured = self.layers(scene).measured(brdf);
+                    self.trace_brdf(rand, &measured);
+                }
+                Bxdf::Specular { cior, k, metal, .. } => {
+                    let (cior, k) = self.conductor_ior(&cior, &k, metal);
+                    self.trace_specular(&cior, &k);
+                }
+                Bxdf::Dielectric { ior, trans_id, .. } | Bxdf::Subsurface { ior, trans_id, .. } => {
+                    let interior = self.interior(scene, trans_id, &ior);
+                    self.trace_dielectric(interior, rand);
+                }
+                Bxdf::MicroBrdf {
+                    ax,
+                    ay,
+                    cior,
+                    k,
+                    metal,
+                    ..
+                } => {
+                    let (cior, k) = self.conductor_ior(&cior, &k, metal);
+                    self.trace_microbrdf(rand, ax, ay, &cior, &k);
+                }
+                Bxdf::MicroBtdf {
+                    ax,
+                    ay,
+                    ior,
+                    trans_id,
+                } => {
+                    let interior = self.interior(scene, trans_id, &ior);
+                    self.trace_microbtdf(rand, ax, ay, interior);
+                }
+                // crossed by cross_interface
+                Bxdf::Medium { .. } => {}
+                // resolved by Scene::select_mix
+                Bxdf::Mix { .. } => {}
+            }
+            if self.leaks(&self.now_ray.dir) {
+                break;
+            }
+            self.throughput = self.throughput * self.shading_correction(&wi, &self.now_ray.dir);
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use camera::{LensModel, PinholeModel};
+use integrator::Integrator;
 use material::Bxdf;
 use math::Vec3;
 use object::{Axis, Object};
+use pathtracing::PathConfig;
 use polygon::read_ply;
 use random::FreshId;
 use render::render;
@@ -9,19 +11,33 @@ use scene::Scene;
 use texture::{load_hdr, make_cdf_hdr, Texture};
 
 mod aabb;
+mod bump;
 mod bvh;
 mod camera;
+mod film;
 mod filter;
+mod integrator;
+mod layered;
+mod lighttracing;
 mod material;
 mod math;
+mod measured;
+mod medium;
+mod mix;
+mod multiscatter;
 mod object;
+mod parammap;
 mod pathtracing;
 mod polygon;
+mod principled;
 mod random;
 mod ray;
 mod render;
 mod scene;
+mod sheen;
+mod spectrum;
 mod texture;
+mod volume;
 
 pub fn example1() {
     let obj_id = &mut FreshId::new();
@@ -89,7 +105,12 @@ pub fn example1() {
         Texture::set_image(&data, &cdf, px_w, px_h),
     );
 
-    let _ = render(&camera, &scene);
+    let _ = render(
+        &camera,
+        &scene,
+        &Integrator::Pathtracing,
+        &PathConfig::new(),
+    );
 }
 
 pub fn cornel_box() {
@@ -218,7 +239,12 @@ pub fn cornel_box() {
 
     let scene = Scene::new(objects, mediums, Texture::set_solid(Vec3::new(0.)));
 
-    let _ = render(&camera, &scene);
+    let _ = render(
+        &camera,
+        &scene,
+        &Integrator::Pathtracing,
+        &PathConfig::new(),
+    );
 }
 
 fn main() {
@@ -2,21 +2,42 @@ use num_complex::Complex;
 // using crate "num-complex", https://github.com/rust-num/num-complex
 
 use crate::{
+    film::Film,
+    layered::Coat,
     math::{cross, dot, fmax, Color, Vec3, EPS, PI},
+    principled::Principled,
     random::XorRand,
+    sheen::Sheen,
+    spectrum::{rgb_to_spectrum, Metal},
 };
 
+// tangent of isotropic lobes, tangent_frame falls back to the world axes
+pub const NO_TANGENT: Vec3 = Vec3(0., 0., 0.);
+
 #[allow(unused)]
 #[derive(Clone, Copy)]
 pub enum Bxdf {
     Lambertian,
+    OrenNayar {
+        sigma: f64, // standard deviation of the facet slope angle [rad]
+    },
     Specular {
         cior: Color,
         k: Color,
+        metal: Option<Metal>, // spectral n, k table, cior and k are used in rgb mode
+        film: Option<Film>,
     },
     Dielectric {
-        ior: f64,
+        ior: Ior,
         trans_id: i32,
+        film: Option<Film>,
+    },
+    // single sided surfaces without an interior, e.g. window panes and leaves
+    ThinDielectric {
+        ior: Ior,
+    },
+    Translucent {
+        transmission: f64, // fraction of the diffuse albedo transmitted to the back side
     },
     Light,
     MicroBrdf {
@@ -24,21 +45,130 @@ pub enum Bxdf {
         ay: f64,
         cior: Color,
         k: Color,
+        metal: Option<Metal>,
+        film: Option<Film>,
     },
     MicroBtdf {
-        a: f64,
-        ior: f64,
+        ax: f64,
+        ay: f64,
+        ior: Ior,
+        trans_id: i32,
+    },
+    Principled {
+        params: Principled,
+        ior: Ior,
+        trans_id: i32,
+    },
+    Sheen {
+        sheen: Sheen,
+        base: i32, // index of Scene::materials, -1 for the sheen alone, see is_layer_base
+    },
+    Coated {
+        coat: Coat,
+        base: i32, // index of Scene::materials, a non-delta reflective bxdf, see is_layer_base
+    },
+    Measured {
+        brdf: i32, // index of Scene::measured
+    },
+    // resolved by Scene::select_mix at each hit
+    Mix {
+        mix: i32, // index of Scene::mixes
+    },
+    // smooth dielectric boundary with a random walk in the interior medium
+    Subsurface {
+        ior: Ior,
+        sigma_a: Color,
+        sigma_s: Color,
         trans_id: i32,
     },
     //not bxdf
     Medium {
-        sigma_a: f64,
-        sigma_s: f64,
-        sigma_e: f64,
+        sigma_a: Color,
+        sigma_s: Color,
+        sigma_e: Color,
+        grid: i32, // index of Scene::grids scaling the coefficients, -1 for homogeneous
+        emission: Color, // emitted radiance, the source term is sigma_a * emission
+        emission_grid: i32, // index of Scene::grids scaling the emission, -1 for constant
+        phase: Phase,
         trans_id: i32,
     },
 }
 
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Isotropic,
+    HenyeyGreenstein { g: f64 },           // g > 0 scatters forward
+    DoubleHg { g1: f64, g2: f64, w: f64 }, // w * hg(g1) + (1 - w) * hg(g2)
+    Rayleigh,
+}
+
+// phase of set_*_medium, back-scattering as the formerly hard-coded lobe
+const DEFAULT_PHASE: Phase = Phase::HenyeyGreenstein { g: -0.8 };
+
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Ior {
+    Constant(f64),
+    Cauchy { a: f64, b: f64 },              // n = a + b / l^2, l in um
+    Sellmeier { b: [f64; 3], c: [f64; 3] }, // n^2 = 1 + sum b_i l^2 / (l^2 - c_i), l in um
+}
+
+#[allow(unused)]
+impl Ior {
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+    pub const FUSED_SILICA: Ior = Ior::Sellmeier {
+        b: [0.6961663, 0.4079426, 0.8974794],
+        c: [0.00467914826, 0.0135120631, 97.9340025],
+    };
+    pub const DIAMOND: Ior = Ior::Sellmeier {
+        b: [0.3306, 4.3356, 0.],
+        c: [0.030625, 0.011236, 0.],
+    };
+    pub const WATER: Ior = Ior::Cauchy {
+        a: 1.3199,
+        b: 0.00653,
+    };
+
+    pub fn eval(&self, lambda: f64) -> f64 {
+        //lambda: wavelength[nm]
+        let l_sq = (lambda * 1e-3).powi(2);
+        match self {
+            Ior::Constant(ior) => *ior,
+            Ior::Cauchy { a, b } => a + b / l_sq,
+            Ior::Sellmeier { b, c } => {
+                let mut n_sq = 1.;
+                for i in 0..3 {
+                    n_sq += b[i] * l_sq / (l_sq - c[i]);
+                }
+                n_sq.sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
+}
+
+// bxdfs sampled and evaluated as a whole by the integrators
+pub trait Brdf {
+    fn eval(&self, color: &Color, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color;
+    fn sample(&self, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3;
+    fn pdf(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64;
+}
+
+#[derive(Clone, Copy)]
+pub enum Lobe {
+    Diffuse,
+    Glossy,
+    Transmission,
+    Volume,
+}
+
 #[allow(unused)]
 impl Bxdf {
     pub fn is_light(&self) -> bool {
@@ -55,19 +185,106 @@ impl Bxdf {
         }
     }
 
+    pub fn is_dispersive(&self) -> bool {
+        match self {
+            Self::ThinDielectric { ior } => ior.is_dispersive(),
+            _ => self
+                .get_dielectric()
+                .is_some_and(|(_, ior)| ior.is_dispersive()),
+        }
+    }
+
+    pub fn get_dielectric(&self) -> Option<(i32, Ior)> {
+        //return (trans_id, ior) of the surfaces refracting into an interior
+        match *self {
+            Self::Dielectric { ior, trans_id, .. }
+            | Self::MicroBtdf { ior, trans_id, .. }
+            | Self::Subsurface { ior, trans_id, .. } => Some((trans_id, ior)),
+            Self::Principled {
+                params,
+                ior,
+                trans_id,
+            } if params.transmission_weight() > 0. => Some((trans_id, ior)),
+            _ => None,
+        }
+    }
+
+    pub fn get_lobe(&self) -> Option<Lobe> {
+        match self {
+            Self::Lambertian
+            | Self::OrenNayar { .. }
+            | Self::Translucent { .. }
+            | Self::Sheen { .. } => Some(Lobe::Diffuse),
+            // the transmission lobe of Principled is chosen by PathState::select_lobe
+            Self::Specular { .. }
+            | Self::MicroBrdf { .. }
+            | Self::Principled { .. }
+            | Self::Coated { .. }
+            | Self::Measured { .. } => Some(Lobe::Glossy),
+            Self::Dielectric { .. }
+            | Self::ThinDielectric { .. }
+            | Self::MicroBtdf { .. }
+            | Self::Subsurface { .. } => Some(Lobe::Transmission),
+            Self::Light | Self::Medium { .. } | Self::Mix { .. } => None,
+        }
+    }
+
+    pub fn set_oren_nayar(sigma: f64) -> Self {
+        Self::OrenNayar { sigma }
+    }
+
     pub fn set_spec_di() -> Self {
         Self::Specular {
             cior: Vec3::new(-1.),
             k: Vec3::new(-1.),
+            metal: None,
+            film: None,
         }
     }
 
     pub fn set_dielectric(ior: f64, trans_id: i32) -> Self {
-        Self::Dielectric { ior, trans_id }
+        Self::Dielectric {
+            ior: Ior::Constant(ior),
+            trans_id,
+            film: None,
+        }
+    }
+
+    pub fn set_dispersive(ior: Ior, trans_id: i32) -> Self {
+        Self::Dielectric {
+            ior,
+            trans_id,
+            film: None,
+        }
+    }
+
+    pub fn set_thin_dielectric(ior: f64) -> Self {
+        Self::ThinDielectric {
+            ior: Ior::Constant(ior),
+        }
+    }
+
+    pub fn set_translucent(transmission: f64) -> Self {
+        Self::Translucent { transmission }
     }
 
     pub fn set_spec_co(cior: Color, k: Color) -> Self {
-        Self::Specular { cior, k }
+        Self::Specular {
+            cior,
+            k,
+            metal: None,
+            film: None,
+        }
+    }
+
+    pub fn set_spec_metal(metal: Metal) -> Self {
+        let (cior, k) = metal.rgb_nk();
+        Self::Specular {
+            cior,
+            k,
+            metal: Some(metal),
+            film: None,
+        }
     }
 
     pub fn set_microbrdf_di(ax: f64, ay: f64) -> Self {
@@ -76,30 +293,314 @@ impl Bxdf {
             ay,
             cior: Vec3::new(-1.),
             k: Vec3::new(-1.),
+            metal: None,
+            film: None,
         }
     }
 
     pub fn set_microbrdf_co(ax: f64, ay: f64, cior: Color, k: Color) -> Self {
-        Self::MicroBrdf { ax, ay, cior, k }
+        Self::MicroBrdf {
+            ax,
+            ay,
+            cior,
+            k,
+            metal: None,
+            film: None,
+        }
+    }
+
+    pub fn set_microbrdf_metal(ax: f64, ay: f64, metal: Metal) -> Self {
+        let (cior, k) = metal.rgb_nk();
+        Self::MicroBrdf {
+            ax,
+            ay,
+            cior,
+            k,
+            metal: Some(metal),
+            film: None,
+        }
     }
 
     pub fn set_microbtdf(a: f64, ior: f64, trans_id: i32) -> Self {
-        Self::MicroBtdf { a, ior, trans_id }
+        Self::MicroBtdf {
+            ax: a,
+            ay: a,
+            ior: Ior::Constant(ior),
+            trans_id,
+        }
+    }
+
+    pub fn set_microbtdf_aniso(ax: f64, ay: f64, ior: f64, trans_id: i32) -> Self {
+        //ax along the tangent of the hit, e.g. the brushing direction
+        Self::MicroBtdf {
+            ax,
+            ay,
+            ior: Ior::Constant(ior),
+            trans_id,
+        }
+    }
+
+    pub fn set_microbtdf_dispersive(a: f64, ior: Ior, trans_id: i32) -> Self {
+        Self::MicroBtdf {
+            ax: a,
+            ay: a,
+            ior,
+            trans_id,
+        }
+    }
+
+    pub fn set_principled(params: Principled, ior: f64, trans_id: i32) -> Self {
+        //trans_id is only used when params.transmission > 0
+        Self::Principled {
+            params,
+            ior: Ior::Constant(ior),
+            trans_id,
+        }
+    }
+
+    pub fn set_sheen(sheen: Sheen) -> Self {
+        Self::Sheen { sheen, base: -1 }
+    }
+
+    pub fn set_sheen_layer(sheen: Sheen, base: i32) -> Self {
+        Self::Sheen { sheen, base }
+    }
+
+    pub fn set_coated(coat: Coat, base: i32) -> Self {
+        Self::Coated { coat, base }
+    }
+
+    pub fn set_measured(brdf: i32) -> Self {
+        Self::Measured { brdf }
+    }
+
+    pub fn set_mix(mix: i32) -> Self {
+        Self::Mix { mix }
+    }
+
+    pub fn set_subsurface(ior: f64, mfp: Color, albedo: Color, trans_id: i32) -> Self {
+        //mfp: mean free path per channel, albedo: multiple scattering albedo per channel
+        let (sigma_a, sigma_s) = subsurface_coefficients(&mfp, &albedo);
+        Self::Subsurface {
+            ior: Ior::Constant(ior),
+            sigma_a,
+            sigma_s,
+            trans_id,
+        }
     }
 
     pub fn set_medium(s_ab: f64, s_sc: f64, trans_id: i32) -> Self {
+        Self::set_color_medium(Vec3::new(s_ab), Vec3::new(s_sc), trans_id)
+    }
+
+    pub fn set_color_medium(s_ab: Color, s_sc: Color, trans_id: i32) -> Self {
+        Self::Medium {
+            sigma_a: s_ab,
+            sigma_s: s_sc,
+            sigma_e: s_ab + s_sc,
+            grid: -1,
+            emission: Vec3::new(0.),
+            emission_grid: -1,
+            phase: DEFAULT_PHASE,
+            trans_id,
+        }
+    }
+
+    pub fn set_grid_medium(s_ab: Color, s_sc: Color, grid: i32, trans_id: i32) -> Self {
         Self::Medium {
             sigma_a: s_ab,
             sigma_s: s_sc,
             sigma_e: s_ab + s_sc,
+            grid,
+            emission: Vec3::new(0.),
+            emission_grid: -1,
+            phase: DEFAULT_PHASE,
             trans_id,
         }
     }
 
-    pub fn get_sigma_ex(&self) -> f64 {
+    pub fn set_emission(mut self, emission: Color, emission_grid: i32) -> Self {
+        if let Self::Medium {
+            emission: e,
+            emission_grid: eg,
+            ..
+        } = &mut self
+        {
+            *e = emission;
+            *eg = emission_grid;
+        }
+        self
+    }
+
+    pub fn set_phase(mut self, phase: Phase) -> Self {
+        if let Self::Medium { phase: p, .. } = &mut self {
+            *p = phase;
+        }
+        self
+    }
+
+    pub fn set_film(mut self, film: Film) -> Self {
+        //thin film on Specular, MicroBrdf and Dielectric
+        match &mut self {
+            Self::Specular { film: f, .. }
+            | Self::MicroBrdf { film: f, .. }
+            | Self::Dielectric { film: f, .. } => *f = Some(film),
+            _ => {}
+        }
+        self
+    }
+
+    pub fn get_film(&self) -> Option<Film> {
+        match *self {
+            Self::Specular { film, .. }
+            | Self::MicroBrdf { film, .. }
+            | Self::Dielectric { film, .. } => film,
+            _ => None,
+        }
+    }
+
+    pub fn get_phase(&self) -> Phase {
+        match self {
+            Self::Medium { phase, .. } => *phase,
+            _ => Phase::Isotropic,
+        }
+    }
+
+    pub fn get_subsurface_medium(&self) -> Option<Self> {
+        match *self {
+            Self::Subsurface {
+                sigma_a,
+                sigma_s,
+                trans_id,
+                ..
+            } => {
+                Some(Self::set_color_medium(sigma_a, sigma_s, trans_id).set_phase(Phase::Isotropic))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_sigma_ex(&self) -> Color {
         match self {
             Self::Medium { sigma_e: e, .. } => *e,
-            _ => -1.,
+            _ => Vec3::new(-1.),
+        }
+    }
+
+    pub fn get_sigma_sc(&self) -> Color {
+        match self {
+            Self::Medium { sigma_s: s, .. } => *s,
+            _ => Vec3::new(-1.),
+        }
+    }
+
+    pub fn get_spectral_medium(&self, lambdas: &Vec3) -> Self {
+        //return the medium with its coefficients at the three wavelengths
+        match *self {
+            Self::Medium {
+                sigma_a,
+                sigma_s,
+                grid,
+                emission,
+                emission_grid,
+                phase,
+                trans_id,
+                ..
+            } => {
+                let sigma_a = rgb_to_spectrum(&sigma_a, lambdas);
+                let sigma_s = rgb_to_spectrum(&sigma_s, lambdas);
+                Self::Medium {
+                    sigma_a,
+                    sigma_s,
+                    sigma_e: sigma_a + sigma_s,
+                    grid,
+                    emission: rgb_to_spectrum(&emission, lambdas),
+                    emission_grid,
+                    phase,
+                    trans_id,
+                }
+            }
+            _ => *self,
+        }
+    }
+
+    pub fn is_layer_base(&self) -> bool {
+        //return true for the non-delta reflective bxdfs a coat or a sheen can lie on
+        match *self {
+            Self::Lambertian
+            | Self::OrenNayar { .. }
+            | Self::Coated { .. }
+            | Self::Sheen { .. }
+            | Self::Measured { .. } => true,
+            Self::MicroBrdf { ax, ay, .. } => ax > 0. && ay > 0.,
+            Self::Principled { params, .. } => params.transmission_weight() == 0.,
+            _ => false,
+        }
+    }
+
+    pub fn immersed(&self, ior: f64) -> Self {
+        //return the bxdf with conductor cior and k relative to a layer of ior above it
+        let mut bxdf = *self;
+        if let Self::MicroBrdf { cior, k, .. } = &mut bxdf {
+            if cior.0 >= 0. {
+                *cior = *cior / ior;
+                *k = *k / ior;
+            }
+        }
+        bxdf
+    }
+}
+
+// Lambertian, OrenNayar or MicroBrdf as the base of layered materials, the other bases have
+// their own Brdf. cior and k of MicroBrdf are already converted for spectral mode
+pub struct BaseBrdf {
+    pub bxdf: Bxdf,
+    pub tangent: Vec3, // tangent of the hit, anisotropic bases rotate with the object
+}
+
+impl Brdf for BaseBrdf {
+    fn eval(&self, color: &Color, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color {
+        match self.bxdf {
+            Bxdf::Lambertian if dot(*wi, *normal) > 0. && dot(*wo, *normal) > 0. => *color / PI,
+            Bxdf::Lambertian => Vec3::new(0.),
+            Bxdf::OrenNayar { sigma } => *color * eval_oren_nayar(sigma, wi, wo, normal),
+            Bxdf::MicroBrdf {
+                ax, ay, cior, k, ..
+            } => {
+                let (brdf, vn) = eval_microbrdf(ax, ay, wi, wo, normal, &self.tangent);
+                if brdf > 0. {
+                    fr_microbrdf(&cior, &k, color, wo, &vn) * brdf
+                } else {
+                    Vec3::new(0.)
+                }
+            }
+            _ => unreachable!("layer bases are checked by Scene::set_materials"),
+        }
+    }
+
+    fn sample(&self, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3 {
+        match self.bxdf {
+            Bxdf::MicroBrdf { ax, ay, .. } => {
+                let vn = sample_ggx_vndf(normal, &self.tangent, wi, ax, ay, rand);
+                reflection_dir(vn, -*wi)
+            }
+            _ => sample_lambert(normal, rand),
+        }
+    }
+
+    fn pdf(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        let cos_i = dot(*wi, *normal);
+        if cos_i <= 0. || dot(*wo, *normal) <= 0. {
+            return 0.;
+        }
+        match self.bxdf {
+            Bxdf::MicroBrdf { ax, ay, .. } => {
+                let vn = (*wi + *wo).normalize();
+                shadow_mask_fn(ggx_alpha2(ax, ay, wi, normal, &self.tangent), wi, normal)
+                    * ggx_normal_df(ax, ay, normal, &self.tangent, &vn)
+                    / (4. * cos_i)
+            }
+            _ => sample_lambert_pdf(wo, normal),
         }
     }
 }
@@ -125,6 +626,24 @@ pub fn sample_lambert_pdf(dir: &Vec3, normal: &Vec3) -> f64 {
     dot(*dir, *normal).abs() / PI
 }
 
+pub fn eval_oren_nayar(sigma: f64, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+    //return brdf without the albedo, Oren and Nayar, "Generalization of Lambert's
+    //Reflectance Model", 1994
+    let cos_i = dot(*wi, *normal);
+    let cos_o = dot(*wo, *normal);
+    if cos_i <= 0. || cos_o <= 0. {
+        return 0.;
+    }
+
+    let sigma_sq = sigma * sigma;
+    let a = 1. - 0.5 * sigma_sq / (sigma_sq + 0.33);
+    let b = 0.45 * sigma_sq / (sigma_sq + 0.09);
+
+    // sin_i * sin_o * cos(phi_i - phi_o)
+    let proj = dot(*wi - *normal * cos_i, *wo - *normal * cos_o);
+    (a + b * fmax(proj, 0.) / cos_i.max(cos_o)) / PI
+}
+
 pub fn reflection_dir(normal: Vec3, in_dir: Vec3) -> Vec3 {
     in_dir + normal * dot(in_dir, normal) * (-2.)
 }
@@ -171,13 +690,29 @@ pub fn refraction_dir(
     }
 }
 
-pub fn sample_ggx_vndf(normal: &Vec3, wi: &Vec3, ax: f64, ay: f64, rand: &mut XorRand) -> Vec3 {
-    let u = if normal.0.abs() > EPS {
+pub fn tangent_frame(normal: &Vec3, tangent: &Vec3) -> (Vec3, Vec3) {
+    //return (u, v) perpendicular to the normal, u along the tangent projected on the surface.
+    //a tangent that is zero or parallel to the normal takes the frame from the world axes
+    let u = *tangent - *normal * dot(*tangent, *normal);
+    let u = if u.length_sq() > EPS * EPS {
+        u.normalize()
+    } else if normal.0.abs() > EPS {
         cross(*normal, Vec3(0., 1., 0.)).normalize()
     } else {
         cross(*normal, Vec3(1., 0., 0.)).normalize()
     };
-    let v = cross(*normal, u);
+    (u, cross(*normal, u))
+}
+
+pub fn sample_ggx_vndf(
+    normal: &Vec3,
+    tangent: &Vec3,
+    wi: &Vec3,
+    ax: f64,
+    ay: f64,
+    rand: &mut XorRand,
+) -> Vec3 {
+    let (u, v) = tangent_frame(normal, tangent);
 
     let ve = Vec3(dot(u, *wi), dot(v, *wi), dot(*normal, *wi));
 
@@ -209,14 +744,18 @@ pub fn shadow_mask_fn(alpha_sq: f64, v: &Vec3, normal: &Vec3) -> f64 {
     2. / (1. + (1. + alpha_sq * tan_theta_sq).sqrt())
 }
 
-pub fn ggx_alpha2(ax: f64, ay: f64, wi: &Vec3, normal: &Vec3) -> f64 {
-    let wi_dash = *wi - *normal * dot(*wi, *normal);
-    let u = cross(*normal, Vec3(0., 1., 0.)).normalize();
-    let v = cross(*normal, u);
-
-    let tan_phi = dot(wi_dash, v) / dot(wi_dash, u);
-    let cos_phi_sq = 1. / (1. + tan_phi * tan_phi);
-    ax * ax * cos_phi_sq + ay * ay * (1. - cos_phi_sq)
+pub fn ggx_alpha2(ax: f64, ay: f64, w: &Vec3, normal: &Vec3, tangent: &Vec3) -> f64 {
+    //return alpha^2 projected on the azimuth of w, used by shadow_mask_fn
+    if ax == ay {
+        return ax * ax;
+    }
+    let (u, v) = tangent_frame(normal, tangent);
+    let (wu, wv) = (dot(*w, u), dot(*w, v));
+    let len_sq = wu * wu + wv * wv;
+    if len_sq == 0. {
+        return ax * ay;
+    }
+    (ax * ax * wu * wu + ay * ay * wv * wv) / len_sq
 }
 
 pub fn fr_dielectric_col(f0: &Color, wi: &Vec3, vn: &Vec3) -> Color {
@@ -236,9 +775,15 @@ pub fn fr_dielectric_ior(into: bool, ior_i: f64, ior_t: f64, wo: &Vec3, vn: &Vec
     r0 + (1. - r0) * c.powf(5.)
 }
 
+pub fn fr_thin_dielectric(ior: f64, wi: &Vec3, normal: &Vec3) -> f64 {
+    //return reflectance of a thin slab, the internal bounces summed as a geometric series
+    let r = fr_dielectric_ior(true, 1., ior, wi, normal);
+    2. * r / (1. + r)
+}
+
 pub fn fr_conductor(cior: &Color, k: &Color, wi: &Vec3, vn: &Vec3) -> Color {
     let cos_theta = dot(*wi, *vn);
-    let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
     let cior = [cior.0, cior.1, cior.2];
     let k = [k.0, k.1, k.2];
     let mut refl = [0.; 3];
@@ -264,39 +809,109 @@ pub fn fr_conductor(cior: &Color, k: &Color, wi: &Vec3, vn: &Vec3) -> Color {
     Vec3(refl[0], refl[1], refl[2])
 }
 
-pub fn ggx_normal_df(alpha_sq: f64, ax: f64, ay: f64, normal: &Vec3, vn: &Vec3) -> f64 {
+pub fn ggx_normal_df(ax: f64, ay: f64, normal: &Vec3, tangent: &Vec3, vn: &Vec3) -> f64 {
     let cos_theta = dot(*vn, *normal);
-    let tan_theta_sq = 1. / (cos_theta * cos_theta) - 1.;
+    if ax == 0. || ay == 0. {
+        return 0.;
+    } else if ax == ay {
+        // isotropic, independent of the tangent frame
+        let alpha_sq = ax * ax;
+        let tan_theta_sq = 1. / (cos_theta * cos_theta) - 1.;
+        let s = 1. + tan_theta_sq / alpha_sq;
+        return 1. / (PI * alpha_sq * cos_theta.powf(4.) * s * s);
+    }
 
-    let vn_dash = *vn - *normal * cos_theta;
-    let u = cross(*normal, Vec3(0., 1., 0.)).normalize();
-    let v = cross(*normal, u);
+    let (u, v) = tangent_frame(normal, tangent);
+    let (hu, hv) = (dot(*vn, u) / ax, dot(*vn, v) / ay);
+    let s = hu * hu + hv * hv + cos_theta * cos_theta;
+    1. / (PI * ax * ay * s * s)
+}
 
-    let tan_phi = dot(vn_dash, v) / dot(vn_dash, u);
-    let cos_phi_sq = 1. / (1. + tan_phi * tan_phi);
+pub fn eval_microbrdf(
+    ax: f64,
+    ay: f64,
+    wi: &Vec3,
+    wo: &Vec3,
+    normal: &Vec3,
+    tangent: &Vec3,
+) -> (f64, Vec3) {
+    //return (D * G / (4 * cos_i * cos_o), half vector), fresnel is left to the caller
+    let cos_i = dot(*wi, *normal);
+    let cos_o = dot(*wo, *normal);
+    let vn = (*wi + *wo).normalize();
+    if cos_i <= 0. || cos_o <= 0. {
+        return (0., vn);
+    }
 
-    if ax == 0. || ay == 0. {
-        0.
+    let d = ggx_normal_df(ax, ay, normal, tangent, &vn);
+    let g = shadow_mask_fn(ggx_alpha2(ax, ay, wi, normal, tangent), wi, normal)
+        * shadow_mask_fn(ggx_alpha2(ax, ay, wo, normal, tangent), wo, normal);
+
+    (d * g / (4. * cos_i * cos_o), vn)
+}
+
+pub fn fr_microbrdf(cior: &Color, k: &Color, f0: &Color, wo: &Vec3, vn: &Vec3) -> Color {
+    if cior.0 < 0. {
+        fr_dielectric_col(f0, wo, vn)
     } else {
-        let s = 1. + (cos_phi_sq / (ax * ax) + (1. - cos_phi_sq) / (ay * ay)) * tan_theta_sq;
-        1. / (PI * alpha_sq * cos_theta.powf(4.) * s * s)
+        fr_conductor(cior, k, wo, vn)
     }
 }
 
+pub fn eval_microbtdf(
+    ax: f64,
+    ay: f64,
+    iors: (f64, f64),
+    wi: &Vec3,
+    wo: &Vec3,
+    normal: &Vec3,
+    tangent: &Vec3,
+) -> f64 {
+    //wi: toward the light, on the side of normal, iors: (ior of that side, ior of the other)
+    let (ior_i, ior_o) = iors;
+    let cos_i = dot(*wi, *normal);
+    let cos_o = dot(*wo, *normal);
+    if cos_i <= 0. || cos_o == 0. {
+        return 0.;
+    }
+
+    let g = shadow_mask_fn(ggx_alpha2(ax, ay, wi, normal, tangent), wi, normal)
+        * shadow_mask_fn(ggx_alpha2(ax, ay, wo, normal, tangent), wo, normal);
+    let r0 = ((ior_i - ior_o) / (ior_i + ior_o)).powi(2);
+    if cos_o > 0. {
+        let vn = (*wi + *wo).normalize();
+        let fresnel = r0 + (1. - r0) * (1. - dot(*wi, vn)).clamp(0., 1.).powi(5);
+        let d = ggx_normal_df(ax, ay, normal, tangent, &vn);
+        return fresnel * d * g / (4. * cos_i * cos_o);
+    }
+
+    let mut vn = -(*wi * ior_i + *wo * ior_o).normalize();
+    if dot(vn, *normal) < 0. {
+        vn = -vn;
+    }
+    let dot_wi_vn = dot(*wi, vn);
+    let dot_wo_vn = dot(*wo, vn);
+    if dot_wi_vn <= 0. || dot_wo_vn >= 0. {
+        return 0.;
+    }
+
+    let fresnel = r0 + (1. - r0) * (1. - dot_wi_vn).clamp(0., 1.).powi(5);
+    let d = ggx_normal_df(ax, ay, normal, tangent, &vn);
+    let denom = ior_i * dot_wi_vn + ior_o * dot_wo_vn;
+
+    dot_wi_vn * dot_wo_vn.abs() * ior_o * ior_o * (1. - fresnel) * d * g
+        / (cos_i * cos_o.abs() * denom * denom)
+}
+
 pub fn micro_btdf_j(ior_i: f64, ior_o: f64, wi: &Vec3, wo: &Vec3, wh: &Vec3) -> f64 {
     let dot_wo_wh = dot(*wo, *wh);
     ior_o * ior_o * dot_wo_wh.abs() / (ior_i * dot(*wi, *wh) + ior_o * dot_wo_wh).powf(2.)
 }
 
-pub fn sample_hg_phase(dir: &Vec3, g: f64, rand: &mut XorRand) -> Vec3 {
+pub fn dir_from_cos(dir: &Vec3, cos_theta: f64, rand: &mut XorRand) -> Vec3 {
+    //return a direction at cos_theta from dir with a uniform azimuth
     let phi = 2. * PI * rand.next01();
-    let cos_theta = if g < EPS {
-        1. - 2. * rand.next01()
-    } else {
-        let tmp = ((1. - g * g) / (1. + g - 2. * g * rand.next01())).powi(2);
-        -1. / (2. * g) * (1. + g * g - tmp)
-    };
-    let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
 
     let w = *dir;
     let u = if w.0.abs() > EPS {
@@ -309,7 +924,80 @@ pub fn sample_hg_phase(dir: &Vec3, g: f64, rand: &mut XorRand) -> Vec3 {
     u * sin_theta * phi.cos() + v * sin_theta * phi.sin() + w * cos_theta
 }
 
+pub fn subsurface_coefficients(mfp: &Color, albedo: &Color) -> (Color, Color) {
+    //return (sigma_a, sigma_s), the single scattering albedo is inverted from the multiple
+    //scattering albedo, Chiang et al. "Practical and Controllable Subsurface Scattering
+    //for Production Path Tracing", 2016
+    let coefficients = |mfp: f64, albedo: f64| {
+        let tmp = 4.09712 + 4.20863 * albedo
+            - (9.59217 + 41.6808 * albedo + 17.7126 * albedo * albedo).sqrt();
+        let sigma_t = 1. / mfp;
+        let sigma_s = sigma_t * (1. - tmp * tmp);
+        (sigma_t - sigma_s, sigma_s)
+    };
+    let (ra, rs) = coefficients(mfp.0, albedo.0);
+    let (ga, gs) = coefficients(mfp.1, albedo.1);
+    let (ba, bs) = coefficients(mfp.2, albedo.2);
+    (Vec3(ra, ga, ba), Vec3(rs, gs, bs))
+}
+
+pub fn sample_hg_phase(dir: &Vec3, g: f64, rand: &mut XorRand) -> Vec3 {
+    //dir: propagation direction of the incoming ray
+    let u = rand.next01();
+    let cos_theta = if g.abs() < EPS {
+        1. - 2. * u
+    } else {
+        let tmp = ((1. - g * g) / (1. - g + 2. * g * u)).powi(2);
+        ((1. + g * g - tmp) / (2. * g)).clamp(-1., 1.)
+    };
+    dir_from_cos(dir, cos_theta, rand)
+}
+
 pub fn hg_phase_pdf(wo: &Vec3, wi: &Vec3, g: f64) -> f64 {
-    let tmp = (1. + g * g + 2. * g * dot(*wo, *wi)).powf(1.5);
+    //wo: propagation direction of the incoming ray, wi: scattered direction
+    let tmp = (1. + g * g - 2. * g * dot(*wo, *wi)).powf(1.5);
     1. / (4. * PI) * (1. - g * g) / tmp
 }
+
+pub fn sample_rayleigh_phase(dir: &Vec3, rand: &mut XorRand) -> Vec3 {
+    // invert the cdf (cos^3 + 3cos + 4) / 8 with Cardano's formula
+    let q = 4. * rand.next01() - 2.;
+    let d = (q * q + 1.).sqrt();
+    let cos_theta = ((q + d).cbrt() + (q - d).cbrt()).clamp(-1., 1.);
+    dir_from_cos(dir, cos_theta, rand)
+}
+
+pub fn rayleigh_phase_pdf(wo: &Vec3, wi: &Vec3) -> f64 {
+    let cos_theta = dot(*wo, *wi);
+    3. / (16. * PI) * (1. + cos_theta * cos_theta)
+}
+
+#[allow(unused)]
+impl Phase {
+    pub fn sample(&self, dir: &Vec3, rand: &mut XorRand) -> Vec3 {
+        match *self {
+            Phase::Isotropic => sample_hg_phase(dir, 0., rand),
+            Phase::HenyeyGreenstein { g } => sample_hg_phase(dir, g, rand),
+            Phase::DoubleHg { g1, g2, w } => {
+                if rand.next01() < w {
+                    sample_hg_phase(dir, g1, rand)
+                } else {
+                    sample_hg_phase(dir, g2, rand)
+                }
+            }
+            Phase::Rayleigh => sample_rayleigh_phase(dir, rand),
+        }
+    }
+
+    pub fn pdf(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        //the phase functions are sampled exactly, pdf is also the phase function value
+        match *self {
+            Phase::Isotropic => 1. / (4. * PI),
+            Phase::HenyeyGreenstein { g } => hg_phase_pdf(wo, wi, g),
+            Phase::DoubleHg { g1, g2, w } => {
+                w * hg_phase_pdf(wo, wi, g1) + (1. - w) * hg_phase_pdf(wo, wi, g2)
+            }
+            Phase::Rayleigh => rayleigh_phase_pdf(wo, wi),
+        }
+    }
+}
@@ -4,7 +4,7 @@ pub const PI: f64 = 3.14159265358979323846;
 pub const INF: f64 = 1e15;
 pub const EPS: f64 = 1e-6;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Vec3(pub f64, pub f64, pub f64);
 
 pub type Point3 = Vec3;
@@ -91,6 +91,10 @@ pub fn max_elm(v: &Vec3) -> f64 {
     max
 }
 
+pub fn avg_elm(v: &Vec3) -> f64 {
+    (v.0 + v.1 + v.2) / 3.
+}
+
 pub fn fmax(a: f64, b: f64) -> f64 {
     if a > b {
         a
@@ -132,3 +136,7 @@ pub fn clamp_vec(v: Vec3, min: f64, max: f64) -> Vec3 {
         v.2.clamp(min, max),
     )
 }
+
+pub fn exp_vec(v: Vec3) -> Vec3 {
+    Vec3(v.0.exp(), v.1.exp(), v.2.exp())
+}
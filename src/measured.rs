@@ -0,0 +1,288 @@
+use std::{fs::File, io::Read};
+
+use crate::{
+    material::{
+        ggx_normal_df, reflection_dir, sample_ggx_vndf, sample_lambert, sample_lambert_pdf,
+        shadow_mask_fn, Brdf, NO_TANGENT,
+    },
+    math::{avg_elm, cross, dot, multiply, Color, Vec3, EPS, PI},
+    random::XorRand,
+    spectrum::rgb_to_spectrum,
+};
+
+const RES_THETA_H: usize = 90;
+const RES_THETA_D: usize = 90;
+const RES_PHI_D: usize = 180;
+const SCALE: Color = Vec3(1. / 1500., 1.15 / 1500., 1.66 / 1500.);
+
+// isotropic brdf table in the MERL format, indexed by the half and difference angles
+// Matusik et al., "A Data-Driven Reflectance Model", 2003
+pub struct MeasuredBrdf {
+    data: Vec<f64>,
+    alpha: f64, // roughness of the GGX lobe fitted to the specular peak, used for sampling
+}
+
+#[allow(unused)]
+impl MeasuredBrdf {
+    pub fn load_merl(path: &str) -> Self {
+        // theta_h, theta_d, phi_d resolutions as i32 followed by the r, g, b tables of f64
+        let mut file = File::open(path).expect("failed to open brdf");
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).expect("failed to read brdf");
+
+        let read_i32 = |i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let dims = (read_i32(0), read_i32(4), read_i32(8));
+        let size = RES_THETA_H * RES_THETA_D * RES_PHI_D;
+        assert!(
+            dims.0 as usize * dims.1 as usize * dims.2 as usize == size,
+            "unexpected brdf resolution"
+        );
+        assert!(bytes.len() >= 12 + size * 3 * 8, "brdf file is too short");
+
+        let data: Vec<f64> = bytes[12..12 + size * 3 * 8]
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        MeasuredBrdf::new(data)
+    }
+
+    pub fn new(data: Vec<f64>) -> Self {
+        //data: r, g, b tables in the MERL order and scale
+        let mut brdf = MeasuredBrdf { data, alpha: 1. };
+        brdf.alpha = brdf.fit_alpha();
+        brdf
+    }
+
+    fn lookup(&self, theta_h: f64, theta_d: f64, phi_d: f64) -> Color {
+        // theta_h is sampled densely around the specular peak
+        let theta_h_idx = if theta_h <= 0. {
+            0
+        } else {
+            ((theta_h / (PI / 2.)).sqrt() * RES_THETA_H as f64) as usize
+        }
+        .min(RES_THETA_H - 1);
+        let theta_d_idx =
+            ((theta_d / (PI / 2.) * RES_THETA_D as f64) as usize).min(RES_THETA_D - 1);
+        // reciprocity, phi_d and phi_d + PI are the same
+        let phi_d = if phi_d < 0. { phi_d + PI } else { phi_d };
+        let phi_d_idx = ((phi_d / PI * RES_PHI_D as f64) as usize).min(RES_PHI_D - 1);
+
+        let idx = (theta_h_idx * RES_THETA_D + theta_d_idx) * RES_PHI_D + phi_d_idx;
+        let size = RES_THETA_H * RES_THETA_D * RES_PHI_D;
+        Vec3(
+            (self.data[idx] * SCALE.0).max(0.),
+            (self.data[idx + size] * SCALE.1).max(0.),
+            (self.data[idx + 2 * size] * SCALE.2).max(0.),
+        )
+    }
+
+    fn fit_alpha(&self) -> f64 {
+        // least squares fit of the GGX distribution to the normalized peak at theta_d = 0
+        let profile: Vec<f64> = (0..RES_THETA_H)
+            .map(|i| {
+                let theta_h = (i as f64 + 0.5) / RES_THETA_H as f64;
+                avg_elm(&self.lookup(theta_h * theta_h * PI / 2., 0., 0.))
+            })
+            .collect();
+        let floor = profile.iter().cloned().fold(f64::INFINITY, f64::min);
+        let peak = profile[0] - floor;
+        if peak <= 0. {
+            return 1.;
+        }
+
+        let error = |alpha: f64| {
+            let alpha_sq = alpha * alpha;
+            profile
+                .iter()
+                .enumerate()
+                .map(|(i, rho)| {
+                    let theta_h = (i as f64 + 0.5) / RES_THETA_H as f64;
+                    let cos = (theta_h * theta_h * PI / 2.).cos();
+                    let ggx = alpha_sq * alpha_sq / (cos * cos * (alpha_sq - 1.) + 1.).powi(2);
+                    ((rho - floor) / peak - ggx).powi(2)
+                })
+                .sum::<f64>()
+        };
+        (0..64)
+            .map(|i| 0.005 * 200f64.powf(i as f64 / 63.))
+            .min_by(|a, b| error(*a).total_cmp(&error(*b)))
+            .unwrap()
+    }
+
+    fn eval(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color {
+        //return the measured rgb brdf
+        let cos_i = dot(*wi, *normal);
+        let cos_o = dot(*wo, *normal);
+        if cos_i <= 0. || cos_o <= 0. {
+            return Vec3::new(0.);
+        }
+
+        // local frame, any tangent works for isotropic data
+        let w = *normal;
+        let u = if w.0.abs() > EPS {
+            cross(w, Vec3(0., 1., 0.)).normalize()
+        } else {
+            cross(w, Vec3(1., 0., 0.)).normalize()
+        };
+        let v = cross(w, u);
+        let to_local = |d: &Vec3| Vec3(dot(*d, u), dot(*d, v), dot(*d, w));
+        let (wi, wo) = (to_local(wi), to_local(wo));
+
+        let half = (wi + wo).normalize();
+        let theta_h = half.2.clamp(-1., 1.).acos();
+        let phi_h = half.1.atan2(half.0);
+        // difference vector, wo in the frame where the half vector is the pole
+        let d = rotate_z(&wo, -phi_h);
+        let d = rotate_y(&d, -theta_h);
+        let theta_d = d.2.clamp(-1., 1.).acos();
+        let phi_d = d.1.atan2(d.0);
+        self.lookup(theta_h, theta_d, phi_d)
+    }
+}
+
+fn rotate_z(d: &Vec3, angle: f64) -> Vec3 {
+    let (s, c) = angle.sin_cos();
+    Vec3(c * d.0 - s * d.1, s * d.0 + c * d.1, d.2)
+}
+
+fn rotate_y(d: &Vec3, angle: f64) -> Vec3 {
+    let (s, c) = angle.sin_cos();
+    Vec3(c * d.0 + s * d.2, d.1, -s * d.0 + c * d.2)
+}
+
+// measured brdf resolved from Scene::measured, tinted by the texture
+pub struct Measured<'a> {
+    pub brdf: &'a MeasuredBrdf,
+    pub lambdas: Option<Vec3>,
+}
+
+impl Measured<'_> {
+    const GGX_PROB: f64 = 0.5;
+}
+
+impl Brdf for Measured<'_> {
+    fn eval(&self, color: &Color, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color {
+        let rgb = self.brdf.eval(wi, wo, normal);
+        let brdf = match self.lambdas {
+            Some(lambdas) => rgb_to_spectrum(&rgb, &lambdas),
+            None => rgb,
+        };
+        multiply(*color, brdf)
+    }
+
+    fn sample(&self, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3 {
+        if rand.next01() < Self::GGX_PROB {
+            let a = self.brdf.alpha;
+            let vn = sample_ggx_vndf(normal, &NO_TANGENT, wi, a, a, rand);
+            reflection_dir(vn, -*wi)
+        } else {
+            sample_lambert(normal, rand)
+        }
+    }
+
+    fn pdf(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        let cos_i = dot(*wi, *normal);
+        if cos_i <= 0. || dot(*wo, *normal) <= 0. {
+            return 0.;
+        }
+        let a = self.brdf.alpha;
+        let alpha_sq = a * a;
+        let vn = (*wi + *wo).normalize();
+        let vndf = shadow_mask_fn(alpha_sq, wi, normal)
+            * ggx_normal_df(a, a, normal, &NO_TANGENT, &vn)
+            / (4. * cos_i);
+        Self::GGX_PROB * vndf + (1. - Self::GGX_PROB) * sample_lambert_pdf(wo, normal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = RES_THETA_H * RES_THETA_D * RES_PHI_D;
+
+    fn table(red: impl Fn(usize) -> f64) -> MeasuredBrdf {
+        //return a brdf whose red table is red(idx) / SCALE.0, without fitting alpha
+        let mut data = vec![0.; SIZE * 3];
+        for (idx, value) in data[..SIZE].iter_mut().enumerate() {
+            *value = red(idx) / SCALE.0;
+        }
+        MeasuredBrdf { data, alpha: 1. }
+    }
+
+    fn rotate_vector(v: &Vec3, axis: &Vec3, angle: f64) -> Vec3 {
+        let (s, c) = angle.sin_cos();
+        *v * c + *axis * (dot(*v, *axis) * (1. - c)) + cross(*axis, *v) * s
+    }
+
+    fn lookup_brdf_val(theta_in: f64, fi_in: f64, theta_out: f64, fi_out: f64) -> usize {
+        //return the table index of the reference reader of the MERL database, BRDFRead.cpp,
+        //where the tables are declared with 360 phi_d samples and read with half of them
+        let dir =
+            |theta: f64, fi: f64| Vec3(theta.sin() * fi.cos(), theta.sin() * fi.sin(), theta.cos());
+        let (d_in, d_out) = (dir(theta_in, fi_in), dir(theta_out, fi_out));
+        let half = (d_in + d_out).normalize();
+        let theta_half = half.2.acos();
+        let fi_half = half.1.atan2(half.0);
+        let temp = rotate_vector(&d_in, &Vec3(0., 0., 1.), -fi_half);
+        let diff = rotate_vector(&temp, &Vec3(0., 1., 0.), -theta_half);
+        let theta_diff = diff.2.acos();
+        let mut fi_diff = diff.1.atan2(diff.0);
+
+        let theta_half_index = if theta_half <= 0. {
+            0
+        } else {
+            let theta_half_deg = theta_half / (PI / 2.) * RES_THETA_H as f64;
+            ((theta_half_deg * RES_THETA_H as f64).sqrt() as usize).min(RES_THETA_H - 1)
+        };
+        let theta_diff_index =
+            ((theta_diff / (PI * 0.5) * RES_THETA_D as f64) as usize).min(RES_THETA_D - 1);
+        if fi_diff < 0. {
+            fi_diff += PI;
+        }
+        let fi_diff_index =
+            ((fi_diff / PI * (RES_PHI_D * 2) as f64 / 2.) as usize).min(RES_PHI_D - 1);
+        fi_diff_index + theta_diff_index * RES_PHI_D + theta_half_index * RES_PHI_D * RES_THETA_D
+    }
+
+    #[test]
+    fn index_matches_merl_reader() {
+        // eval uses wo for the difference vector where the reader uses wi, the phi_d + PI
+        // fold makes them the same bin
+        let brdf = table(|idx| idx as f64);
+        let mut rand = XorRand::new(7);
+        let normal = Vec3(0., 0., 1.);
+        for _ in 0..10000 {
+            let (theta_in, theta_out) = (rand.next01() * PI / 2., rand.next01() * PI / 2.);
+            let (fi_in, fi_out) = (rand.next01() * 2. * PI, rand.next01() * 2. * PI);
+            let dir = |theta: f64, fi: f64| {
+                Vec3(theta.sin() * fi.cos(), theta.sin() * fi.sin(), theta.cos())
+            };
+            let (wi, wo) = (dir(theta_in, fi_in), dir(theta_out, fi_out));
+            let expected = lookup_brdf_val(theta_in, fi_in, theta_out, fi_out);
+            for (wi, wo) in [(wi, wo), (wo, wi)] {
+                let idx = brdf.eval(&wi, &wo, &normal).0.round() as usize;
+                assert_eq!(idx, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn fit_alpha_of_ggx_table() {
+        // the peak of a GGX lobe over a constant diffuse floor
+        let alpha: f64 = 0.2;
+        let alpha_sq = alpha * alpha;
+        let brdf = table(|idx| {
+            let i = idx / (RES_THETA_D * RES_PHI_D);
+            let theta_h = ((i as f64 + 0.5) / RES_THETA_H as f64).powi(2) * PI / 2.;
+            let cos = theta_h.cos();
+            alpha_sq / (PI * (cos * cos * (alpha_sq - 1.) + 1.).powi(2)) + 0.1
+        });
+        let fitted = brdf.fit_alpha();
+        assert!(
+            (fitted - alpha).abs() < 0.05 * alpha,
+            "fitted alpha {}",
+            fitted
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use crate::material::Bxdf;
+
+// region enclosed by a dielectric surface or a medium boundary, identified by trans_id
+#[derive(Clone, Copy)]
+pub struct Interior {
+    pub trans_id: i32,
+    pub ior: Option<f64>, // None for medium boundaries, which are index matched
+    pub medium: Option<Bxdf>,
+    pub priority: i32, // overlapping regions resolve to the highest priority
+}
+
+impl Interior {
+    pub fn new(trans_id: i32, priority: i32, medium: Option<Bxdf>) -> Self {
+        //medium: interior medium of the dielectric surfaces with trans_id
+        Interior {
+            trans_id,
+            ior: None,
+            medium,
+            priority,
+        }
+    }
+}
+
+// dielectrics and media containing the current vertex of a path
+// Schmidt and Budge, "Simple Nested Dielectrics in Ray Traced Images", 2002
+#[derive(Clone)]
+pub struct MediumStack {
+    stack: Vec<Interior>,
+}
+
+impl MediumStack {
+    pub fn new() -> Self {
+        MediumStack {
+            stack: vec![Interior {
+                trans_id: -1,
+                ior: Some(1.),
+                medium: None,
+                priority: i32::MIN,
+            }],
+        }
+    }
+
+    fn current(&self) -> &Interior {
+        // the latest one wins among equal priorities
+        self.stack.iter().max_by_key(|i| i.priority).unwrap()
+    }
+
+    pub fn get_medium(&self) -> Option<Bxdf> {
+        self.current().medium
+    }
+
+    pub fn has_medium(&self) -> bool {
+        self.get_medium().is_some()
+    }
+
+    pub fn outer_ior(&self, trans_id: i32) -> f64 {
+        //return the ior on the other side of the dielectric surface with trans_id
+        self.stack
+            .iter()
+            .filter(|i| i.ior.is_some() && i.trans_id != trans_id)
+            .max_by_key(|i| i.priority)
+            .and_then(|i| i.ior)
+            .unwrap()
+    }
+
+    pub fn is_into(&self, trans_id: i32) -> bool {
+        self.stack.iter().all(|i| i.trans_id != trans_id)
+    }
+
+    pub fn is_false_hit(&self, trans_id: i32, priority: i32) -> bool {
+        // the surface lies inside a dielectric of higher priority and is skipped
+        self.stack
+            .iter()
+            .any(|i| i.ior.is_some() && i.trans_id != trans_id && i.priority > priority)
+    }
+
+    pub fn cross(&mut self, interior: Interior) {
+        if self.is_into(interior.trans_id) {
+            self.stack.push(interior);
+        } else {
+            self.remove(interior.trans_id);
+        }
+    }
+
+    pub fn remove(&mut self, trans_id: i32) {
+        if let Some(idx) = self.stack.iter().position(|i| i.trans_id == trans_id) {
+            self.stack.remove(idx);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    material::Bxdf,
+    math::{avg_elm, Vec3},
+    texture::Texture,
+};
+
+pub struct MixEntry<'a> {
+    pub bxdf: Bxdf,
+    pub texture: Option<Texture<'a>>, // base color, None keeps the texture of the object
+    pub weight: Texture<'a>,          // the average of the channels at the hit uv
+}
+
+// stochastic blend of bxdfs, one of them is selected at each hit with probability
+// proportional to its weight
+pub struct Mix<'a> {
+    pub entries: Vec<MixEntry<'a>>,
+}
+
+#[allow(unused)]
+impl<'a> Mix<'a> {
+    pub fn new() -> Self {
+        Mix {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add(mut self, bxdf: Bxdf, weight: f64) -> Self {
+        self.entries.push(MixEntry {
+            bxdf,
+            texture: None,
+            weight: Texture::set_solid(Vec3::new(weight)),
+        });
+        self
+    }
+
+    pub fn add_masked(
+        mut self,
+        bxdf: Bxdf,
+        texture: Option<Texture<'a>>,
+        mask: Texture<'a>,
+    ) -> Self {
+        //mask: weight looked up at the hit uv, e.g. rust or dirt
+        self.entries.push(MixEntry {
+            bxdf,
+            texture,
+            weight: mask,
+        });
+        self
+    }
+
+    pub fn select(&self, u: f64, v: f64, rand01: f64) -> &MixEntry<'a> {
+        //return the entry selected by rand01, the first one when all the weights are 0
+        let weight = |entry: &MixEntry| avg_elm(&entry.weight.get_color(u, v)).max(0.);
+        let sum: f64 = self.entries.iter().map(weight).sum();
+
+        let mut acc = 0.;
+        for entry in &self.entries {
+            acc += weight(entry);
+            if rand01 * sum < acc {
+                return entry;
+            }
+        }
+        &self.entries[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_by_channel_average() {
+        // (0.3, 0, 0) weighs as much as a solid 0.1
+        let mix = Mix::new().add(Bxdf::Lambertian, 0.1).add_masked(
+            Bxdf::set_oren_nayar(0.5),
+            None,
+            Texture::set_solid(Vec3(0.3, 0., 0.)),
+        );
+        assert!(std::ptr::eq(mix.select(0.5, 0.5, 0.49), &mix.entries[0]));
+        assert!(std::ptr::eq(mix.select(0.5, 0.5, 0.51), &mix.entries[1]));
+    }
+
+    #[test]
+    fn select_first_when_weights_are_zero() {
+        let mix = Mix::new()
+            .add(Bxdf::Lambertian, 0.)
+            .add(Bxdf::set_oren_nayar(0.5), -1.);
+        for rand01 in [0., 0.5, 0.999] {
+            assert!(std::ptr::eq(mix.select(0.5, 0.5, rand01), &mix.entries[0]));
+        }
+    }
+}
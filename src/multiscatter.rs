@@ -0,0 +1,228 @@
+use std::sync::OnceLock;
+
+use crate::{
+    material::{reflection_dir, refraction_dir, sample_ggx_vndf, shadow_mask_fn, NO_TANGENT},
+    math::{Color, Vec3, PI},
+    random::XorRand,
+};
+
+// energy of GGX lost by single scattering, restored from directional albedos tabulated on
+// first use. conductors get the multiple scattering lobe of Kulla and Conty, "Revisiting
+// Physically Based Shading at Imageworks", 2017. dielectrics keep the shape of their lobes
+// and are divided by the albedo of reflection and transmission, as in Turquin, "Practical
+// multiple scattering compensation for microfacet models", 2019
+
+const RES: usize = 32; // cos and roughness of the conductor table
+const RES_DI: usize = 16; // cos, roughness and ior ratio of the dielectric table
+const MAX_IOR: f64 = 3.;
+const SAMPLES: usize = 512;
+
+static CONDUCTOR_TABLE: OnceLock<(Vec<f64>, Vec<f64>)> = OnceLock::new(); // (E, E_avg)
+static DIELECTRIC_TABLE: OnceLock<Vec<f64>> = OnceLock::new();
+
+pub fn ggx_albedo(alpha: f64, cos: f64) -> f64 {
+    //return directional albedo of GGX with a white fresnel
+    let (albedo, _) = CONDUCTOR_TABLE.get_or_init(make_conductor_table);
+    lookup(albedo, [RES, RES], [cos, alpha.max(0.).sqrt()])
+}
+
+fn ggx_albedo_avg(alpha: f64) -> f64 {
+    let (_, avg) = CONDUCTOR_TABLE.get_or_init(make_conductor_table);
+    lookup(avg, [RES], [alpha.max(0.).sqrt()])
+}
+
+pub fn eval_ms_conductor(alpha: f64, f0: &Color, cos_i: f64, cos_o: f64) -> Color {
+    //return the multiple scattering lobe, f0: reflectance at normal incidence
+    if cos_i <= 0. || cos_o <= 0. {
+        return Vec3::new(0.);
+    }
+    let e_avg = ggx_albedo_avg(alpha);
+    if e_avg >= 1. {
+        return Vec3::new(0.);
+    }
+
+    // hemispherical average of the fresnel, exact for Schlick
+    let f_avg = *f0 * (20. / 21.) + Vec3::new(1. / 21.);
+    let fresnel = |f: f64| f * f * e_avg / (1. - f * (1. - e_avg));
+    let f_ms = Vec3(fresnel(f_avg.0), fresnel(f_avg.1), fresnel(f_avg.2));
+    let lobe =
+        (1. - ggx_albedo(alpha, cos_i)) * (1. - ggx_albedo(alpha, cos_o)) / (PI * (1. - e_avg));
+    f_ms * lobe
+}
+
+pub fn dielectric_albedo(alpha: f64, eta: f64, into: bool, cos: f64) -> f64 {
+    //return albedo of reflection and transmission of a rough dielectric, eta: ior of the
+    //material over the outer one, into: the direction is outside
+    let table = DIELECTRIC_TABLE.get_or_init(make_dielectric_table);
+    let half = RES_DI * RES_DI * RES_DI;
+    let table = if into { &table[..half] } else { &table[half..] };
+    lookup(
+        table,
+        [RES_DI, RES_DI, RES_DI],
+        [cos.abs(), alpha.max(0.).sqrt(), (eta - 1.) / (MAX_IOR - 1.)],
+    )
+    .max(0.1)
+}
+
+fn lookup<const N: usize>(table: &[f64], res: [usize; N], x: [f64; N]) -> f64 {
+    //return multilinear interpolation, x in [0, 1] at the grid points i / (res - 1),
+    //the first dimension is the innermost
+    let mut base = 0;
+    let mut stride = 1;
+    let mut cells = [(0, 0.); N];
+    for ((r, x), cell) in res.iter().zip(x.iter()).zip(cells.iter_mut()) {
+        let t = x.clamp(0., 1.) * (r - 1) as f64;
+        let i = (t as usize).min(r - 2);
+        base += i * stride;
+        *cell = (stride, t - i as f64);
+        stride *= r;
+    }
+
+    (0..1 << N)
+        .map(|corner: usize| {
+            cells
+                .iter()
+                .enumerate()
+                .fold((base, 1.), |(idx, w), (d, (stride, t))| {
+                    if corner >> d & 1 == 1 {
+                        (idx + stride, w * t)
+                    } else {
+                        (idx, w * (1. - t))
+                    }
+                })
+        })
+        .map(|(idx, w)| table[idx] * w)
+        .sum()
+}
+
+fn albedo_sample(alpha: f64, cos: f64, eta: Option<(f64, bool)>, rand: &mut XorRand) -> f64 {
+    //return the weight of a VNDF sample, as traced by the integrators
+    let normal = Vec3(0., 0., 1.);
+    let cos = cos.max(0.001);
+    let wi = Vec3((1. - cos * cos).sqrt(), 0., cos);
+    let vn = sample_ggx_vndf(&normal, &NO_TANGENT, &wi, alpha, alpha, rand);
+
+    let (dir, valid) = match eta {
+        Some((eta, into)) => {
+            let (is_refract, dir, ..) = refraction_dir(into, 1., eta, vn, -wi, rand);
+            (dir, (dir.2 < 0.) == is_refract)
+        }
+        None => {
+            let dir = reflection_dir(vn, -wi);
+            (dir, dir.2 > 0.)
+        }
+    };
+    if valid {
+        shadow_mask_fn(alpha * alpha, &dir, &normal)
+    } else {
+        0.
+    }
+}
+
+fn make_conductor_table() -> (Vec<f64>, Vec<f64>) {
+    // rows are roughness and columns are cos, E_avg = 2 * int E cos dcos
+    let mut rand = XorRand::new(1);
+    let mut albedo = vec![0.; RES * RES];
+    let mut avg = vec![0.; RES];
+    for j in 0..RES {
+        let roughness = j as f64 / (RES - 1) as f64;
+        let alpha = roughness * roughness;
+        for i in 0..RES {
+            let cos = i as f64 / (RES - 1) as f64;
+            albedo[j * RES + i] = (0..SAMPLES)
+                .map(|_| albedo_sample(alpha, cos, None, &mut rand))
+                .sum::<f64>()
+                / SAMPLES as f64;
+        }
+        // trapezoidal rule over the grid, the halves cancel the factor 2
+        avg[j] = (0..RES - 1)
+            .map(|i| {
+                let (c0, c1) = (
+                    i as f64 / (RES - 1) as f64,
+                    (i + 1) as f64 / (RES - 1) as f64,
+                );
+                (albedo[j * RES + i] * c0 + albedo[j * RES + i + 1] * c1) * (c1 - c0)
+            })
+            .sum();
+    }
+    (albedo, avg)
+}
+
+fn make_dielectric_table() -> Vec<f64> {
+    // entering then exiting, each indexed by (ior ratio, roughness, cos)
+    let mut rand = XorRand::new(1);
+    let mut table = Vec::with_capacity(2 * RES_DI * RES_DI * RES_DI);
+    for into in [true, false] {
+        for k in 0..RES_DI {
+            let eta = 1. + (MAX_IOR - 1.) * k as f64 / (RES_DI - 1) as f64;
+            for j in 0..RES_DI {
+                let roughness = j as f64 / (RES_DI - 1) as f64;
+                for i in 0..RES_DI {
+                    let cos = i as f64 / (RES_DI - 1) as f64;
+                    let e = (0..SAMPLES)
+                        .map(|_| {
+                            albedo_sample(roughness * roughness, cos, Some((eta, into)), &mut rand)
+                        })
+                        .sum::<f64>()
+                        / SAMPLES as f64;
+                    table.push(e);
+                }
+            }
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{eval_microbrdf, sample_lambert};
+
+    #[test]
+    fn smooth_albedo_is_one() {
+        for cos in [0.1, 0.5, 1.] {
+            assert!((ggx_albedo(0., cos) - 1.).abs() < 0.01, "cos {}", cos);
+        }
+    }
+
+    #[test]
+    fn average_albedo_decreases_with_alpha() {
+        let avg: Vec<f64> = [0.05, 0.2, 0.4, 0.7, 1.]
+            .iter()
+            .map(|alpha| ggx_albedo_avg(*alpha))
+            .collect();
+        assert!(avg.windows(2).all(|w| w[1] < w[0]), "{:?}", avg);
+    }
+
+    #[test]
+    fn white_furnace_conductor() {
+        // single and multiple scattering of a white conductor reflect everything
+        let normal = Vec3(0., 0., 1.);
+        let f0 = Vec3::new(1.);
+        let mut rand = XorRand::new(3);
+        let n = 200000;
+        for alpha in [0.3, 0.8] {
+            for cos_i in [0.3f64, 0.9] {
+                let wi = Vec3((1. - cos_i * cos_i).sqrt(), 0., cos_i);
+                // cosine weighted sampling, f * cos / pdf = f * PI
+                let energy = (0..n)
+                    .map(|_| {
+                        let wo = sample_lambert(&normal, &mut rand);
+                        let (single, _) =
+                            eval_microbrdf(alpha, alpha, &wi, &wo, &normal, &NO_TANGENT);
+                        let multiple = eval_ms_conductor(alpha, &f0, cos_i, wo.2);
+                        (single + multiple.0) * PI
+                    })
+                    .sum::<f64>()
+                    / n as f64;
+                assert!(
+                    (energy - 1.).abs() < 0.03,
+                    "alpha {} cos {}: {}",
+                    alpha,
+                    cos_i,
+                    energy
+                );
+            }
+        }
+    }
+}
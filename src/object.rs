@@ -1,4 +1,6 @@
 use crate::aabb::AABB;
+use crate::bump::ShadingMap;
+use crate::parammap::ParamMaps;
 use crate::random::{FreshId, XorRand};
 use crate::ray::*;
 use crate::texture::Texture;
@@ -17,6 +19,9 @@ pub enum Object<'a> {
         radius: f64,
         bxdf: Bxdf,
         texture: Texture<'a>,
+        tangent: Option<Vec3>,
+        shading: Option<ShadingMap<'a>>,
+        params: Option<ParamMaps<'a>>,
         obj_id: i32,
         bbox: AABB,
     },
@@ -27,6 +32,9 @@ pub enum Object<'a> {
         max_p: Point3,
         bxdf: Bxdf,
         texture: Texture<'a>,
+        tangent: Option<Vec3>,
+        shading: Option<ShadingMap<'a>>,
+        params: Option<ParamMaps<'a>>,
         obj_id: i32,
         bbox: AABB,
     },
@@ -38,6 +46,9 @@ pub enum Object<'a> {
         normal: Vec3,
         bxdf: Bxdf,
         texture: Texture<'a>,
+        tangent: Option<Vec3>,
+        shading: Option<ShadingMap<'a>>,
+        params: Option<ParamMaps<'a>>,
         obj_id: i32,
         bbox: AABB,
     },
@@ -56,6 +67,9 @@ impl<'a> Object<'a> {
             radius,
             bxdf,
             texture,
+            tangent: None,
+            shading: None,
+            params: None,
             obj_id: freshid.gen_id(),
             bbox: AABB {
                 min_p: center - Vec3::new(radius),
@@ -81,6 +95,9 @@ impl<'a> Object<'a> {
             max_p,
             bxdf,
             texture,
+            tangent: None,
+            shading: None,
+            params: None,
             obj_id: freshid.gen_id(),
             bbox: AABB { min_p, max_p }.rev_aabb(),
         }
@@ -112,6 +129,9 @@ impl<'a> Object<'a> {
             normal,
             bxdf,
             texture,
+            tangent: None,
+            shading: None,
+            params: None,
             obj_id: freshid.gen_id(),
             bbox: AABB { min_p, max_p }.rev_aabb(),
         }
@@ -124,18 +144,42 @@ impl<'a> Object<'a> {
                 radius,
                 bxdf,
                 texture,
+                tangent,
+                shading,
+                params,
                 obj_id: id,
                 ..
             } => {
-                if let Some((t, hitpos, normal)) = hit_sphere(center, radius, ray, record.distance)
-                {
+                // the far side is seen through a cutout of the near side
+                let cut = |hit: &(f64, Point3, Vec3)| {
+                    let uv = sphere_uv(&hit.1, center);
+                    params.as_ref().is_some_and(|maps| maps.is_cut(uv))
+                };
+                let near = hit_sphere(center, radius, ray, 0., record.distance);
+                let hit = match near {
+                    Some(h) if cut(&h) => {
+                        hit_sphere(center, radius, ray, h.0, record.distance).filter(|h| !cut(h))
+                    }
+                    _ => near,
+                };
+                if let Some((t, hitpos, normal)) = hit {
                     record.distance = t;
                     record.pos = hitpos;
                     record.normal = normal;
+                    // along the parallels, zero at the poles
+                    record.tangent = tangent.unwrap_or(Vec3(normal.2, 0., -normal.0));
                     record.bxdf = *bxdf;
                     let (u, v) = sphere_uv(&hitpos, center);
+                    record.shading_normal = match shading {
+                        Some(map) => map.perturb(&normal, &sphere_dpduv(&normal, *radius), (u, v)),
+                        None => normal,
+                    };
                     record.color = texture.get_color(u, v);
+                    if let Some(maps) = params {
+                        maps.apply(&mut record.bxdf, &mut record.color, (u, v));
+                    }
                     record.obj_id = *id;
+                    record.uv = (u, v);
                     true
                 } else {
                     false
@@ -147,19 +191,32 @@ impl<'a> Object<'a> {
                 max_p,
                 bxdf,
                 texture,
+                tangent,
+                shading,
+                params,
                 obj_id: id,
                 ..
             } => {
                 if let Some((t, hitpos, normal, (u, v))) =
                     hit_rect(axis, max_p, min_p, ray, record.distance)
+                        .filter(|(.., uv)| !params.as_ref().is_some_and(|maps| maps.is_cut(*uv)))
                 {
                     record.distance = t;
                     record.pos = hitpos;
                     record.normal = normal;
+                    record.tangent = tangent.unwrap_or(rect_tangent(axis));
+                    record.shading_normal = match shading {
+                        Some(map) => map.perturb(&normal, &rect_dpduv(axis, max_p, min_p), (u, v)),
+                        None => normal,
+                    };
                     record.bxdf = *bxdf;
 
                     record.color = texture.get_color(u, v);
+                    if let Some(maps) = params {
+                        maps.apply(&mut record.bxdf, &mut record.color, (u, v));
+                    }
                     record.obj_id = *id;
+                    record.uv = (u, v);
                     true
                 } else {
                     false
@@ -172,18 +229,32 @@ impl<'a> Object<'a> {
                 normal,
                 bxdf,
                 texture,
+                tangent,
+                shading,
+                params,
                 obj_id: id,
                 ..
             } => {
                 if let Some((t, pos, (u, v))) =
                     hit_triangle(p, pq, pr, normal, ray, record.distance)
+                        .filter(|(.., uv)| !params.as_ref().is_some_and(|maps| maps.is_cut(*uv)))
                 {
                     record.distance = t;
                     record.pos = pos;
                     record.normal = *normal;
+                    record.tangent = tangent.unwrap_or(*pq);
+                    // u, v are the barycentric coordinates along pq and pr
+                    record.shading_normal = match shading {
+                        Some(map) => map.perturb(normal, &(*pq, *pr), (u, v)),
+                        None => *normal,
+                    };
                     record.bxdf = *bxdf;
                     record.color = texture.get_color(u, v);
+                    if let Some(maps) = params {
+                        maps.apply(&mut record.bxdf, &mut record.color, (u, v));
+                    }
                     record.obj_id = *id;
+                    record.uv = (u, v);
                     true
                 } else {
                     false
@@ -192,6 +263,49 @@ impl<'a> Object<'a> {
         }
     }
 
+    pub fn set_tangent(mut self, dir: Vec3) -> Self {
+        //dir: orientation of anisotropic bxdfs, projected on the surface at each hit.
+        //by default it follows the direction of increasing u
+        match &mut self {
+            Object::Sphere { tangent, .. }
+            | Object::Rectangle { tangent, .. }
+            | Object::Triangle { tangent, .. } => *tangent = Some(dir),
+        }
+        self
+    }
+
+    #[allow(unused)]
+    pub fn set_bump(self, height: Texture<'a>, scale: f64) -> Self {
+        //height: displaced along the normal by height * scale, in the texture coordinates
+        self.set_shading(ShadingMap::set_bump(height, scale))
+    }
+
+    #[allow(unused)]
+    pub fn set_normal_map(self, texture: Texture<'a>) -> Self {
+        //texture: tangent space normals, the tangent follows the direction of increasing u
+        self.set_shading(ShadingMap::set_normal(texture))
+    }
+
+    #[allow(unused)]
+    pub fn set_param_maps(mut self, maps: ParamMaps<'a>) -> Self {
+        //maps: textures replacing the constant parameters of the bxdf at each hit
+        match &mut self {
+            Object::Sphere { params, .. }
+            | Object::Rectangle { params, .. }
+            | Object::Triangle { params, .. } => *params = Some(maps),
+        }
+        self
+    }
+
+    fn set_shading(mut self, map: ShadingMap<'a>) -> Self {
+        match &mut self {
+            Object::Sphere { shading, .. }
+            | Object::Rectangle { shading, .. }
+            | Object::Triangle { shading, .. } => *shading = Some(map),
+        }
+        self
+    }
+
     pub fn get_bxdf(&'a self) -> &'a Bxdf {
         match self {
             Object::Sphere { bxdf, .. }
@@ -218,7 +332,7 @@ impl<'a> Object<'a> {
 
     pub fn get_area(&self) -> f64 {
         match self {
-            Object::Sphere { radius, .. } => 2. * PI * radius,
+            Object::Sphere { radius, .. } => 4. * PI * radius * radius,
             Object::Rectangle {
                 axis, min_p, max_p, ..
             } => match axis {
@@ -234,6 +348,102 @@ impl<'a> Object<'a> {
         let bbox = self.get_bbox();
         (bbox.min_p + bbox.max_p) / 2.
     }
+
+    fn emission_at(&self, texture: &Texture, uv: (f64, f64)) -> Color {
+        //return the color of a sampled point, with the emission map and the cutout of lights
+        let (u, v) = uv;
+        let mut color = texture.get_color(u, v);
+        match self {
+            Object::Sphere {
+                bxdf,
+                params: Some(maps),
+                ..
+            }
+            | Object::Rectangle {
+                bxdf,
+                params: Some(maps),
+                ..
+            }
+            | Object::Triangle {
+                bxdf,
+                params: Some(maps),
+                ..
+            } => {
+                if maps.is_cut(uv) {
+                    return Vec3::new(0.);
+                }
+                let mut bxdf = *bxdf;
+                maps.apply(&mut bxdf, &mut color, uv);
+            }
+            _ => (),
+        }
+        color
+    }
+
+    pub fn sample_surface(&self, rand: &mut XorRand) -> (Point3, Vec3, Color) {
+        //return (position, normal, color), sampled uniformly by area
+        match self {
+            Object::Sphere {
+                center,
+                radius,
+                texture,
+                ..
+            } => {
+                let cos_theta = 1. - 2. * rand.next01();
+                let sin_theta = (1. - cos_theta * cos_theta).sqrt();
+                let phi = 2. * PI * rand.next01();
+                let normal = Vec3(sin_theta * phi.cos(), cos_theta, sin_theta * phi.sin());
+                let pos = *center + normal * *radius;
+                let (u, v) = sphere_uv(&pos, center);
+                (pos, normal, self.emission_at(texture, (u, v)))
+            }
+            Object::Rectangle {
+                axis,
+                min_p,
+                max_p,
+                texture,
+                ..
+            } => {
+                let d = *max_p - *min_p;
+                let (pos, normal) = match axis {
+                    Axis::X => (
+                        *min_p + Vec3(0., d.1 * rand.next01(), d.2 * rand.next01()),
+                        Vec3(1., 0., 0.),
+                    ),
+                    Axis::Y => (
+                        *min_p + Vec3(d.0 * rand.next01(), 0., d.2 * rand.next01()),
+                        Vec3(0., 1., 0.),
+                    ),
+                    Axis::Z => (
+                        *min_p + Vec3(d.0 * rand.next01(), d.1 * rand.next01(), 0.),
+                        Vec3(0., 0., 1.),
+                    ),
+                };
+                let (u, v) = rect_uv(axis, max_p, min_p, &pos);
+                (pos, normal, self.emission_at(texture, (u, v)))
+            }
+            Object::Triangle {
+                p,
+                pq,
+                pr,
+                normal,
+                texture,
+                ..
+            } => {
+                let mut r1 = rand.next01();
+                let mut r2 = rand.next01();
+                if r1 + r2 > 1. {
+                    r1 = 1. - r1;
+                    r2 = 1. - r2;
+                }
+                (
+                    *p + *pq * r1 + *pr * r2,
+                    *normal,
+                    self.emission_at(texture, (r1, r2)),
+                )
+            }
+        }
+    }
 }
 
 pub fn sphere_uv(p: &Point3, center: &Point3) -> (f64, f64) {
@@ -248,9 +458,10 @@ fn hit_sphere(
     center: &Point3,
     radius: &f64,
     ray: &Ray,
+    min_dist: f64,
     max_dist: f64,
 ) -> Option<(f64, Point3, Vec3)> {
-    //if hit, return (distant,normal)
+    //if hit beyond min_dist, return (distant,normal)
     let oc = *center - ray.org;
     let oc_dir = dot(oc, ray.dir);
     let disc = oc_dir * oc_dir - oc.length_sq() + radius * radius;
@@ -263,9 +474,9 @@ fn hit_sphere(
     let t2 = oc_dir + disc.sqrt();
     let t;
 
-    if t1 > 0. {
+    if t1 > min_dist {
         t = t1;
-    } else if t2 > 0. {
+    } else if t2 > min_dist {
         t = t2;
     } else {
         return None;
@@ -335,8 +546,6 @@ pub fn hit_rect(
 
     if let Some((t, normal)) = hit_plane(&axis, &pos, ray, max_dist) {
         let hitpoint = ray.org + ray.dir * t;
-        let d = *max_p - *min_p;
-        let v = hitpoint - *min_p;
         match axis {
             Axis::X => {
                 if hitpoint.1 > max_p.1
@@ -346,7 +555,7 @@ pub fn hit_rect(
                 {
                     return None;
                 } else {
-                    return Some((t, hitpoint, normal, (1. - v.2 / d.2, 1. - v.1 / d.1)));
+                    return Some((t, hitpoint, normal, rect_uv(axis, max_p, min_p, &hitpoint)));
                 }
             }
             Axis::Y => {
@@ -357,7 +566,7 @@ pub fn hit_rect(
                 {
                     return None;
                 } else {
-                    return Some((t, hitpoint, normal, (v.0 / d.0, v.2 / d.2)));
+                    return Some((t, hitpoint, normal, rect_uv(axis, max_p, min_p, &hitpoint)));
                 }
             }
             Axis::Z => {
@@ -368,7 +577,7 @@ pub fn hit_rect(
                 {
                     return None;
                 } else {
-                    return Some((t, hitpoint, normal, (v.0 / d.0, 1. - v.1 / d.1)));
+                    return Some((t, hitpoint, normal, rect_uv(axis, max_p, min_p, &hitpoint)));
                 }
             }
         }
@@ -376,6 +585,49 @@ pub fn hit_rect(
     None
 }
 
+fn sphere_dpduv(normal: &Vec3, radius: f64) -> (Vec3, Vec3) {
+    //return (dp/du, dp/dv) of sphere_uv, dp/dv is zero at the poles
+    let sin_theta = (normal.0 * normal.0 + normal.2 * normal.2).sqrt();
+    let dpdu = Vec3(normal.2, 0., -normal.0) * (2. * PI * radius);
+    if sin_theta < EPS {
+        return (dpdu, Vec3::new(0.));
+    }
+    let dpdv = Vec3(
+        normal.1 * normal.0 / sin_theta,
+        -sin_theta,
+        normal.1 * normal.2 / sin_theta,
+    ) * (PI * radius);
+    (dpdu, dpdv)
+}
+
+fn rect_dpduv(axis: &Axis, max_p: &Point3, min_p: &Point3) -> (Vec3, Vec3) {
+    //return (dp/du, dp/dv) of rect_uv
+    let d = *max_p - *min_p;
+    match axis {
+        Axis::X => (Vec3(0., 0., -d.2), Vec3(0., -d.1, 0.)),
+        Axis::Y => (Vec3(d.0, 0., 0.), Vec3(0., 0., d.2)),
+        Axis::Z => (Vec3(d.0, 0., 0.), Vec3(0., -d.1, 0.)),
+    }
+}
+
+fn rect_tangent(axis: &Axis) -> Vec3 {
+    //return the direction of increasing u of rect_uv
+    match axis {
+        Axis::X => Vec3(0., 0., -1.),
+        Axis::Y | Axis::Z => Vec3(1., 0., 0.),
+    }
+}
+
+fn rect_uv(axis: &Axis, max_p: &Point3, min_p: &Point3, pos: &Point3) -> (f64, f64) {
+    let d = *max_p - *min_p;
+    let v = *pos - *min_p;
+    match axis {
+        Axis::X => (1. - v.2 / d.2, 1. - v.1 / d.1),
+        Axis::Y => (v.0 / d.0, v.2 / d.2),
+        Axis::Z => (v.0 / d.0, 1. - v.1 / d.1),
+    }
+}
+
 pub fn hit_triangle(
     p: &Point3,
     pq: &Point3,
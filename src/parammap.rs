@@ -0,0 +1,157 @@
+use crate::{
+    film::Film,
+    material::{Bxdf, Ior},
+    math::{avg_elm, Color},
+    texture::Texture,
+};
+
+const MIN_ALPHA: f64 = 0.001; // smallest ggx alpha left by a roughness map
+
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Param {
+    // r in [0, 1], ggx alpha = r^2, roughness of Principled and Sheen, OrenNayar sigma = r
+    Roughness,
+    Metallic, // Principled::metallic
+    // ior of thin dielectrics and coats, dispersive iors become constant. the ior of a
+    // dielectric bounding an interior is kept, the medium stack reads it on both sides
+    Ior,
+    Emission,       // emitted radiance of lights
+    Transmission,   // Translucent transmission
+    CoatThickness,  // Coat::thickness
+    CoatAbsorption, // Coat::sigma_a
+    FilmThickness,  // Film::thickness [nm]
+    SheenColor,     // Sheen::color, of Bxdf::Sheen
+    Specular,       // the weights of Principled below
+    SpecularTint,
+    Sheen,
+    SheenTint,
+    Clearcoat,
+    ClearcoatGloss,
+}
+
+// textures driving the parameters of the bxdf and the opacity of an object, looked up at the
+// hit uv. a map holds the parameter value itself and replaces the constant of the bxdf, scalar
+// parameters use the average of the channels. delta lobes stay smooth under roughness maps,
+// Principled::transmission stays constant as it decides whether the surface bounds an
+// interior. the entries of a Bxdf::Mix keep their constants
+pub struct ParamMaps<'a> {
+    pub maps: Vec<(Param, Texture<'a>)>,
+    pub opacity: Option<Texture<'a>>, // cutout, the surface is missed below the cutoff
+    pub cutoff: f64,
+}
+
+#[allow(unused)]
+impl<'a> ParamMaps<'a> {
+    pub fn new() -> Self {
+        ParamMaps {
+            maps: Vec::new(),
+            opacity: None,
+            cutoff: 0.5,
+        }
+    }
+
+    pub fn set_map(mut self, param: Param, texture: Texture<'a>) -> Self {
+        self.maps.push((param, texture));
+        self
+    }
+
+    pub fn set_roughness(self, texture: Texture<'a>) -> Self {
+        self.set_map(Param::Roughness, texture)
+    }
+
+    pub fn set_metallic(self, texture: Texture<'a>) -> Self {
+        self.set_map(Param::Metallic, texture)
+    }
+
+    pub fn set_ior(self, texture: Texture<'a>) -> Self {
+        self.set_map(Param::Ior, texture)
+    }
+
+    pub fn set_emission(self, texture: Texture<'a>) -> Self {
+        self.set_map(Param::Emission, texture)
+    }
+
+    pub fn set_opacity(mut self, texture: Texture<'a>, cutoff: f64) -> Self {
+        //texture: alpha of foliage and fences, cutoff: 0.5 as the alphaCutoff of glTF
+        self.opacity = Some(texture);
+        self.cutoff = cutoff;
+        self
+    }
+
+    pub fn is_cut(&self, uv: (f64, f64)) -> bool {
+        //return true when the surface is missing at uv, for camera and shadow rays alike
+        let (u, v) = uv;
+        self.opacity
+            .as_ref()
+            .is_some_and(|t| avg_elm(&t.get_color(u, v)) < self.cutoff)
+    }
+
+    pub fn apply(&self, bxdf: &mut Bxdf, color: &mut Color, uv: (f64, f64)) {
+        //bxdf and color: the constants of the object, replaced by the values at uv
+        let (u, v) = uv;
+        for (param, texture) in &self.maps {
+            let value = texture.get_color(u, v);
+            apply_param(*param, value, avg_elm(&value), bxdf, color);
+        }
+    }
+}
+
+fn apply_param(param: Param, value: Color, x: f64, bxdf: &mut Bxdf, color: &mut Color) {
+    //value: the color of the map, x: its average for scalar parameters
+    match (param, bxdf) {
+        (Param::Roughness, bxdf) => {
+            let r = x.clamp(0., 1.);
+            match bxdf {
+                Bxdf::OrenNayar { sigma } => *sigma = r,
+                Bxdf::MicroBrdf { ax, ay, .. } | Bxdf::MicroBtdf { ax, ay, .. }
+                    if *ax > 0. && *ay > 0. =>
+                {
+                    // the aspect ratio of anisotropic lobes is kept
+                    let alpha = (r * r).max(MIN_ALPHA);
+                    let aspect = (*ax / *ay).sqrt();
+                    (*ax, *ay) = (alpha * aspect, alpha / aspect);
+                }
+                Bxdf::Principled { params, .. } => params.roughness = r,
+                Bxdf::Sheen { sheen, .. } => sheen.roughness = r,
+                Bxdf::Coated { coat, .. } => coat.a = r * r,
+                _ => (),
+            }
+        }
+        (Param::Metallic, Bxdf::Principled { params, .. }) => params.metallic = x.clamp(0., 1.),
+        (Param::Ior, bxdf) => {
+            let n = x.max(1.);
+            match bxdf {
+                Bxdf::ThinDielectric { ior } => *ior = Ior::Constant(n),
+                Bxdf::Coated { coat, .. } => coat.ior = n,
+                _ => (),
+            }
+        }
+        (Param::Emission, Bxdf::Light) => *color = value,
+        (Param::Transmission, Bxdf::Translucent { transmission }) => {
+            *transmission = x.clamp(0., 1.)
+        }
+        (Param::CoatThickness, Bxdf::Coated { coat, .. }) => coat.thickness = x.max(0.),
+        (Param::CoatAbsorption, Bxdf::Coated { coat, .. }) => coat.sigma_a = value,
+        (Param::FilmThickness, bxdf) => {
+            if let Some(film) = bxdf.get_film() {
+                *bxdf = bxdf.set_film(Film {
+                    thickness: x.max(0.),
+                    ..film
+                });
+            }
+        }
+        (Param::SheenColor, Bxdf::Sheen { sheen, .. }) => sheen.color = Some(value),
+        (Param::Specular, Bxdf::Principled { params, .. }) => params.specular = x.clamp(0., 1.),
+        (Param::SpecularTint, Bxdf::Principled { params, .. }) => {
+            params.specular_tint = x.clamp(0., 1.)
+        }
+        (Param::Sheen, Bxdf::Principled { params, .. }) => params.sheen = x.clamp(0., 1.),
+        (Param::SheenTint, Bxdf::Principled { params, .. }) => params.sheen_tint = x.clamp(0., 1.),
+        (Param::Clearcoat, Bxdf::Principled { params, .. }) => params.clearcoat = x.clamp(0., 1.),
+        (Param::ClearcoatGloss, Bxdf::Principled { params, .. }) => {
+            params.clearcoat_gloss = x.clamp(0., 1.)
+        }
+        _ => (),
+    }
+}
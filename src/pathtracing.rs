@@ -1,92 +1,497 @@
+use std::ops::{Deref, DerefMut};
+
 use crate::{
+    film::sample_film_dielectric,
+    layered::{Coat, Layered},
     material::*,
     math::{dot, fmax, fmin, max_elm, multiply, Color, Vec3, EPS, PI},
+    measured::Measured,
+    medium::{Interior, MediumStack},
+    multiscatter::{dielectric_albedo, eval_ms_conductor, ggx_albedo},
     object::sphere_uv,
     random::XorRand,
-    ray::{HitRecord, Ray},
+    ray::{HitRecord, NeeResult, Ray},
     scene::Scene,
+    sheen::{Sheen, SheenLayer},
+    spectrum::{
+        conductor_spectrum, rgb_to_spectrum, sample_wavelengths, spectrum_to_rgb, Metal, LAMBDA_D,
+        LAMBDA_RGB,
+    },
     texture::{sample_hdr_pdf, Texture},
 };
 
-const DEPTH: u32 = 6;
-const MAX_DEPTH: u32 = 30;
 const PI_INV: f64 = 1. / PI;
 
-pub struct Pathtracing {
-    record: HitRecord,
-    now_ray: Ray,
-    roulette_pdf: f64,
-    orienting_normal: Vec3,
-    throughput: Vec3,
-    rad: Color,
-    pt_sample_pdf: f64,
-    medium_stack: Vec<(i32, f64, f64, f64)>, // (trans_id, ior, sigma_scatter, sigma_extinct)
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Roulette {
+    Albedo,     // survival probability from the color of the hit surface
+    Throughput, // survival probability from the path throughput
 }
 
-impl Pathtracing {
-    pub fn new(ray: Ray) -> Self {
-        Pathtracing {
+#[derive(Clone, Copy)]
+pub struct PathConfig {
+    pub min_depth: u32,   // no russian roulette up to this depth
+    pub decay_depth: u32, // survival probability halves per bounce after this depth
+    pub max_depth: u32,   // paths are cut after this many scattering events
+    pub roulette: Roulette,
+    pub max_diffuse: u32,
+    pub max_glossy: u32,
+    pub max_transmission: u32,
+    pub max_volume: u32,
+    pub spectral: bool,     // hero wavelength sampling, the result is still rgb
+    pub multiscatter: bool, // energy compensation of rough MicroBrdf and MicroBtdf, off by default
+}
+
+impl PathConfig {
+    pub fn new() -> Self {
+        PathConfig {
+            min_depth: 6,
+            decay_depth: 30,
+            max_depth: u32::MAX,
+            roulette: Roulette::Albedo,
+            max_diffuse: u32::MAX,
+            max_glossy: u32::MAX,
+            max_transmission: u32::MAX,
+            max_volume: u32::MAX,
+            spectral: false,
+            multiscatter: false,
+        }
+    }
+
+    pub fn max_bounces(&self, lobe: Lobe) -> u32 {
+        match lobe {
+            Lobe::Diffuse => self.max_diffuse,
+            Lobe::Glossy => self.max_glossy,
+            Lobe::Transmission => self.max_transmission,
+            Lobe::Volume => self.max_volume,
+        }
+    }
+
+    pub fn roulette(&self, depth: u32, record: &HitRecord, throughput: &Color) -> f64 {
+        //throughput: already divided by the roulette pdf
+        if depth <= self.min_depth || record.bxdf.is_light() {
+            return 1.;
+        }
+
+        let prob = match self.roulette {
+            Roulette::Albedo => fmin(max_elm(&record.color), 1.),
+            Roulette::Throughput => fmin(max_elm(throughput), 1.),
+        };
+
+        if depth > self.decay_depth {
+            prob / 2_i32.pow(depth - self.decay_depth) as f64
+        } else {
+            prob
+        }
+    }
+}
+
+pub struct Bounces {
+    total: u32,
+    per_lobe: [u32; 4],
+}
+
+impl Bounces {
+    pub fn new() -> Self {
+        Bounces {
+            total: 0,
+            per_lobe: [0; 4],
+        }
+    }
+
+    pub fn get_total(&self) -> u32 {
+        self.total
+    }
+
+    pub fn next(&mut self, lobe: Lobe, config: &PathConfig) -> bool {
+        //count a scattering event, return false when it exceeds the limits
+        let count = &mut self.per_lobe[lobe as usize];
+        if self.total >= config.max_depth || *count >= config.max_bounces(lobe) {
+            return false;
+        }
+        *count += 1;
+        self.total += 1;
+        true
+    }
+}
+
+// the vertex of a path shared by Pathtracing and LightTracing, both deref to it
+pub struct PathState {
+    pub record: HitRecord,
+    pub now_ray: Ray,
+    pub roulette_pdf: f64,
+    pub orienting_normal: Vec3, // shading normal on the side of the incoming ray
+    pub geometric_normal: Vec3, // surface normal on the same side, for ray offsets and leaks
+    pub throughput: Color,
+    pub medium_stack: MediumStack,
+    pub config: PathConfig,
+    pub bounces: Bounces,
+    pub lambdas: Option<Vec3>,
+    pub single_lambda: bool,
+}
+
+impl PathState {
+    pub fn new(ray: Ray, throughput: Color, config: PathConfig) -> Self {
+        PathState {
             record: HitRecord::new(),
             now_ray: ray,
             roulette_pdf: 1.,
             orienting_normal: Vec3::new(0.),
-            throughput: Vec3::new(1.),
-            rad: Vec3::new(0.),
-            pt_sample_pdf: -1.,
-            medium_stack: vec![(-1, 1., -1., 0.)],
+            geometric_normal: Vec3::new(0.),
+            throughput,
+            medium_stack: MediumStack::new(),
+            config,
+            bounces: Bounces::new(),
+            lambdas: None,
+            single_lambda: false,
+        }
+    }
+
+    pub fn select_lobe(&self, rand: &mut XorRand) -> Option<Lobe> {
+        //return the lobe of the scattering event at the hit, None for lights and media
+        match self.record.bxdf {
+            // the transmission lobe is a rough dielectric, always taken from inside
+            Bxdf::Principled {
+                params, trans_id, ..
+            } => {
+                let tw = params.transmission_weight();
+                if tw > 0. && (!self.medium_stack.is_into(trans_id) || rand.next01() < tw) {
+                    Some(Lobe::Transmission)
+                } else {
+                    Some(Lobe::Glossy)
+                }
+            }
+            bxdf => bxdf.get_lobe(),
         }
     }
 
-    fn get_sigma_e(&self) -> f64 {
-        self.medium_stack.last().unwrap().3
+    pub fn next_bounce(&mut self, lobe: Lobe) -> bool {
+        self.bounces.next(lobe, &self.config)
     }
 
-    fn is_into(&self, trans_id: i32) -> bool {
-        for (id, ..) in self.medium_stack.iter() {
-            if *id == trans_id {
-                return false;
-            }
+    pub fn to_spectrum(&self, color: &Color) -> Color {
+        match self.lambdas {
+            Some(lambdas) => rgb_to_spectrum(color, &lambdas),
+            None => *color,
+        }
+    }
+
+    pub fn spectral_medium(&self, medium: &Bxdf) -> Bxdf {
+        match self.lambdas {
+            Some(lambdas) => medium.get_spectral_medium(&lambdas),
+            None => *medium,
+        }
+    }
+
+    pub fn select_ior(&self, ior: &Ior) -> f64 {
+        match self.lambdas {
+            Some(lambdas) => ior.eval(lambdas.0),
+            None => ior.eval(LAMBDA_D),
+        }
+    }
+
+    pub fn collapse_wavelengths(&mut self) {
+        // a dispersive interface splits the wavelengths, only the hero one is kept
+        if self.lambdas.is_some() && !self.single_lambda {
+            self.throughput = Vec3(self.throughput.0 * 3., 0., 0.);
+            self.single_lambda = true;
+        }
+    }
+
+    pub fn conductor_ior(&self, cior: &Color, k: &Color, metal: Option<Metal>) -> (Color, Color) {
+        match self.lambdas {
+            Some(lambdas) if cior.0 >= 0. => conductor_spectrum(cior, k, metal, &lambdas),
+            _ => (*cior, *k),
+        }
+    }
+
+    pub fn roulette(&self) -> f64 {
+        self.config.roulette(
+            self.bounces.get_total(),
+            &self.record,
+            &(self.throughput / self.roulette_pdf),
+        )
+    }
+
+    pub fn orient_normals(&mut self) {
+        let side = if dot(self.record.normal, self.now_ray.dir) < 0. {
+            1.
+        } else {
+            -1.
+        };
+        self.geometric_normal = self.record.normal * side;
+        self.orienting_normal = self.record.shading_normal * side;
+    }
+
+    pub fn leaks(&self, dir: &Vec3) -> bool {
+        //return true when the shading and geometric normals disagree on the side of dir
+        dot(*dir, self.orienting_normal) * dot(*dir, self.geometric_normal) < 0.
+    }
+
+    pub fn interior(&self, scene: &Scene, trans_id: i32, ior: &Ior) -> Interior {
+        let interior = scene.get_interior(trans_id);
+        let medium = self.record.bxdf.get_subsurface_medium().or(interior.medium);
+        Interior {
+            ior: Some(self.select_ior(ior)),
+            medium: medium.map(|medium| self.spectral_medium(&medium)),
+            ..interior
         }
+    }
+
+    pub fn cross_interface(&mut self, scene: &Scene) -> bool {
+        //return true when the hit is a medium boundary or a dielectric surface skipped by
+        //priority, the ray goes straight through it
+        let interior = match self.record.bxdf {
+            Bxdf::Medium { trans_id, .. } => Interior {
+                medium: Some(self.spectral_medium(&self.record.bxdf)),
+                ..scene.get_interior(trans_id)
+            },
+            _ => {
+                let Some((trans_id, ior)) = self.record.bxdf.get_dielectric() else {
+                    return false;
+                };
+                let interior = self.interior(scene, trans_id, &ior);
+                if !self.medium_stack.is_false_hit(trans_id, interior.priority) {
+                    return false;
+                }
+                interior
+            }
+        };
+
+        self.medium_stack.cross(interior);
+        self.now_ray = Ray {
+            org: self.record.pos - self.geometric_normal * 0.00001,
+            dir: self.now_ray.dir,
+        };
         true
     }
 
-    fn has_ior(&self) -> bool {
-        for (_, ior, ..) in self.medium_stack.iter() {
-            if *ior != 1. {
-                return true;
+    fn film_lambdas(&self) -> Vec3 {
+        //return the wavelengths a thin film is evaluated at, LAMBDA_RGB in rgb mode
+        self.lambdas
+            .unwrap_or(Vec3(LAMBDA_RGB[0], LAMBDA_RGB[1], LAMBDA_RGB[2]))
+    }
+
+    pub fn fresnel(&self, cior: &Color, k: &Color, wo: &Vec3, vn: &Vec3) -> Color {
+        //return reflectance of Specular and MicroBrdf, with the thin film of the hit bxdf
+        match self.record.bxdf.get_film() {
+            Some(film) => {
+                film.fr_surface(cior, k, &self.record.color, wo, vn, &self.film_lambdas())
             }
+            None => fr_microbrdf(cior, k, &self.record.color, wo, vn),
         }
-        false
     }
 
-    fn has_medium(&self) -> bool {
-        self.medium_stack.last().unwrap().2 >= 0.
+    pub fn btdf_compensation(&self, ax: f64, ay: f64, eta: f64, into: bool, w: &Vec3) -> f64 {
+        //return the multiple scattering scale of a rough dielectric seen from w, wi when
+        //tracing from the camera and wo when tracing from the lights
+        let alpha = (ax * ay).sqrt();
+        if self.config.multiscatter && alpha > 0. {
+            1. / dielectric_albedo(alpha, eta, into, dot(*w, self.orienting_normal))
+        } else {
+            1.
+        }
     }
 
-    fn remove_medium(&mut self, trans_id: i32) {
-        if let Some(idx) = self.medium_stack.iter().position(|(x, ..)| *x == trans_id) {
-            self.medium_stack.remove(idx);
+    pub fn sample_dielectric(
+        &self,
+        into: bool,
+        ior_out: f64,
+        ior_mat: f64,
+        rand: &mut XorRand,
+    ) -> (bool, Vec3, Color, f64) {
+        //return (is_refract, new_dir, fresnel, prob), with the thin film of the hit bxdf
+        match self.record.bxdf.get_film() {
+            Some(film) => {
+                let (ior_i, ior_t) = if into {
+                    (ior_out, ior_mat)
+                } else {
+                    (ior_mat, ior_out)
+                };
+                sample_film_dielectric(
+                    &film,
+                    ior_i,
+                    ior_t,
+                    &self.orienting_normal,
+                    &self.now_ray.dir,
+                    &self.film_lambdas(),
+                    rand,
+                )
+            }
+            None => {
+                let (is_refract, dir, fresnel, prob) = refraction_dir(
+                    into,
+                    ior_out,
+                    ior_mat,
+                    self.orienting_normal,
+                    self.now_ray.dir,
+                    rand,
+                );
+                (is_refract, dir, Vec3::new(fresnel), prob)
+            }
         }
     }
 
-    fn roulette(&self, time: u32) -> f64 {
-        let mut prob = match self.record.bxdf {
-            Bxdf::Light => 1.,
-            _ => fmin(max_elm(&self.record.color), 1.),
-        };
+    pub fn layers<'b>(&self, scene: &'b Scene<'b>) -> Layers<'b> {
+        Layers {
+            scene,
+            lambdas: self.lambdas,
+            tangent: self.record.tangent,
+        }
+    }
+}
+
+// Scene::materials at the wavelengths and tangent of a hit, copied out of PathState so that
+// the layered materials built from it do not borrow the integrator
+#[derive(Clone, Copy)]
+pub struct Layers<'a> {
+    scene: &'a Scene<'a>,
+    lambdas: Option<Vec3>,
+    tangent: Vec3,
+}
 
-        if time > MAX_DEPTH {
-            prob /= 2_i32.pow(time - MAX_DEPTH) as f64;
-        } else if time <= DEPTH {
-            prob = 1.;
+impl<'a> Layers<'a> {
+    pub fn layered(&self, coat: &Coat, base: i32) -> Layered<LayerBase<'a>> {
+        Layered {
+            coat: Coat {
+                sigma_a: self
+                    .lambdas
+                    .map_or(coat.sigma_a, |l| rgb_to_spectrum(&coat.sigma_a, &l)),
+                ..*coat
+            },
+            base: LayerBase {
+                layers: *self,
+                id: base,
+                ior: coat.ior,
+            },
+            lobe: self.scene.materials[base as usize].get_lobe(),
         }
+    }
 
-        prob
+    pub fn sheen_layer(&self, sheen: &Sheen, base: i32) -> SheenLayer<LayerBase<'a>> {
+        SheenLayer {
+            sheen: Sheen {
+                color: sheen
+                    .color
+                    .map(|color| self.lambdas.map_or(color, |l| rgb_to_spectrum(&color, &l))),
+                ..*sheen
+            },
+            base: (base >= 0).then_some(LayerBase {
+                layers: *self,
+                id: base,
+                ior: 1.,
+            }),
+        }
+    }
+
+    pub fn measured(&self, brdf: i32) -> Measured<'a> {
+        Measured {
+            brdf: self.scene.measured[brdf as usize],
+            lambdas: self.lambdas,
+        }
+    }
+}
+
+// base of a layered material, resolved from Scene::materials on every use so that layers
+// over layers are evaluated without allocating
+#[derive(Clone, Copy)]
+pub struct LayerBase<'a> {
+    layers: Layers<'a>,
+    id: i32,  // index of Scene::materials
+    ior: f64, // ior of the layer above it
+}
+
+impl LayerBase<'_> {
+    fn with<R>(&self, f: impl FnOnce(&dyn Brdf) -> R) -> R {
+        let layers = &self.layers;
+        match layers.scene.materials[self.id as usize] {
+            Bxdf::Coated { coat, base } => f(&layers.layered(&coat, base)),
+            Bxdf::Sheen { sheen, base } => f(&layers.sheen_layer(&sheen, base)),
+            Bxdf::Measured { brdf } => f(&layers.measured(brdf)),
+            Bxdf::Principled { params, .. } => f(&params),
+            mut bxdf => {
+                if let (Bxdf::MicroBrdf { cior, k, metal, .. }, Some(lambdas)) =
+                    (&mut bxdf, layers.lambdas)
+                {
+                    if cior.0 >= 0. {
+                        (*cior, *k) = conductor_spectrum(cior, k, *metal, &lambdas);
+                    }
+                }
+                f(&BaseBrdf {
+                    bxdf: bxdf.immersed(self.ior),
+                    tangent: layers.tangent,
+                })
+            }
+        }
+    }
+}
+
+impl Brdf for LayerBase<'_> {
+    fn eval(&self, color: &Color, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color {
+        self.with(|base| base.eval(color, wi, wo, normal))
+    }
+
+    fn sample(&self, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3 {
+        self.with(|base| base.sample(wi, normal, rand))
+    }
+
+    fn pdf(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        self.with(|base| base.pdf(wi, wo, normal))
+    }
+}
+
+pub struct Pathtracing {
+    state: PathState,
+    rad: Color,
+    pt_sample_pdf: f64,
+}
+
+impl Deref for Pathtracing {
+    type Target = PathState;
+
+    fn deref(&self) -> &PathState {
+        &self.state
+    }
+}
+
+impl DerefMut for Pathtracing {
+    fn deref_mut(&mut self) -> &mut PathState {
+        &mut self.state
+    }
+}
+
+impl Pathtracing {
+    pub fn new(ray: Ray, config: PathConfig) -> Self {
+        Pathtracing {
+            state: PathState::new(ray, Vec3::new(1.), config),
+            rad: Vec3::new(0.),
+            pt_sample_pdf: -1.,
+        }
+    }
+
+    pub fn get_depth(&self) -> u32 {
+        self.bounces.get_total()
+    }
+
+    fn nee(&self, scene: &Scene, org: Vec3, rand: &mut XorRand) -> NeeResult {
+        //return NeeResult whose color is already attenuated by the media on the shadow ray
+        let (mut nee_result, transmittance) =
+            scene.nee(org, rand, &self.medium_stack, self.lambdas);
+        nee_result.color = multiply(self.to_spectrum(&nee_result.color), transmittance);
+        if self.leaks(&nee_result.dir) {
+            return NeeResult::new();
+        }
+        nee_result
     }
 
     fn ray_intersect(&mut self, scene: &Scene) -> bool {
         self.record = HitRecord::new();
-        if !scene.intersect(&self.now_ray, &mut self.record, &scene.bvh_tree[0]) {
+        if !scene.intersect(
+            &self.state.now_ray,
+            &mut self.state.record,
+            &scene.bvh_tree[0],
+        ) {
             let (u, v) = sphere_uv(&self.now_ray.dir, &Vec3::new(0.));
 
             let mis_weight = if let Texture::ImageTex {
@@ -103,7 +508,7 @@ impl Pathtracing {
                 1.
             };
 
-            let background = scene.background.get_color(u, v);
+            let background = self.to_spectrum(&scene.background.get_color(u, v));
             self.rad =
                 self.rad + multiply(self.throughput, background) * mis_weight / self.roulette_pdf;
             return false;
@@ -128,21 +533,119 @@ impl Pathtracing {
         self.now_ray = Ray { org, dir };
 
         self.throughput = multiply(self.throughput, self.record.color);
-        let (nee_result, transmittance) = scene.nee(org, rand, self.get_sigma_e());
+        let nee_result = self.nee(scene, org, rand);
 
         if nee_result.pdf != 0. {
             let nee_dir_cos = fmax(dot(self.orienting_normal, nee_result.dir), 0.);
             let mis_weight = 1. / (nee_result.pdf + nee_dir_cos * PI_INV);
             self.rad = self.rad
-                + multiply(self.throughput, nee_result.color * PI_INV)
+                + multiply(self.throughput, nee_result.color * PI_INV) * nee_dir_cos * mis_weight
+                    / self.roulette_pdf;
+        }
+        self.pt_sample_pdf = sample_lambert_pdf(&dir, &self.orienting_normal);
+    }
+
+    fn trace_translucent(&mut self, scene: &Scene, rand: &mut XorRand, transmission: f64) {
+        let normal = self.orienting_normal;
+        let side = if rand.next01() < transmission {
+            -normal
+        } else {
+            normal
+        };
+        let dir = sample_lambert(&side, rand);
+
+        // shadow rays from both sides, each one only counts the lights on its side
+        self.throughput = multiply(self.throughput, self.record.color);
+        for (side_n, weight) in [(normal, 1. - transmission), (-normal, transmission)] {
+            if weight <= 0. {
+                continue;
+            }
+            let nee_result = self.nee(scene, self.record.pos + side_n * 0.00001, rand);
+            let nee_dir_cos = dot(side_n, nee_result.dir);
+            if nee_result.pdf != 0. && nee_dir_cos > 0. {
+                let mis_weight = 1. / (nee_result.pdf + weight * nee_dir_cos * PI_INV);
+                self.rad = self.rad
+                    + multiply(self.throughput, nee_result.color)
+                        * (weight * PI_INV * nee_dir_cos * mis_weight)
+                        / self.roulette_pdf;
+            }
+        }
+
+        let side_prob = if dot(side, normal) > 0. {
+            1. - transmission
+        } else {
+            transmission
+        };
+        self.now_ray = Ray {
+            org: self.record.pos + side * 0.00001,
+            dir,
+        };
+        self.pt_sample_pdf = side_prob * sample_lambert_pdf(&dir, &side);
+    }
+
+    fn trace_oren_nayar(&mut self, scene: &Scene, rand: &mut XorRand, sigma: f64) {
+        let wi = -self.now_ray.dir;
+        let dir = sample_lambert(&self.orienting_normal, rand);
+        let org = self.record.pos + self.orienting_normal * 0.00001;
+        self.now_ray = Ray { org, dir };
+
+        let nee_result = self.nee(scene, org, rand);
+        if nee_result.pdf != 0. {
+            let nee_dir_cos = fmax(dot(self.orienting_normal, nee_result.dir), 0.);
+            let brdf = eval_oren_nayar(sigma, &wi, &nee_result.dir, &self.orienting_normal);
+            let mis_weight = 1. / (nee_result.pdf + nee_dir_cos * PI_INV);
+            self.rad = self.rad
+                + multiply(
+                    multiply(self.throughput, self.record.color),
+                    nee_result.color,
+                ) * brdf
                     * nee_dir_cos
-                    * transmittance
                     * mis_weight
                     / self.roulette_pdf;
         }
+
+        // cosine weighted sampling, brdf * cos / pdf = brdf * PI
+        let brdf = eval_oren_nayar(sigma, &wi, &dir, &self.orienting_normal);
+        self.throughput = multiply(self.throughput, self.record.color) * brdf * PI;
         self.pt_sample_pdf = sample_lambert_pdf(&dir, &self.orienting_normal);
     }
 
+    fn trace_brdf(&mut self, scene: &Scene, rand: &mut XorRand, brdf: &impl Brdf) {
+        let wi = -self.now_ray.dir;
+        let dir = brdf.sample(&wi, &self.orienting_normal, rand);
+        let org = self.record.pos + self.orienting_normal * 0.00001;
+        self.now_ray = Ray { org, dir };
+
+        let nee_result = self.nee(scene, org, rand);
+        if nee_result.pdf != 0. {
+            let nee_dir_cos = fmax(dot(self.orienting_normal, nee_result.dir), 0.);
+            let f = brdf.eval(
+                &self.record.color,
+                &wi,
+                &nee_result.dir,
+                &self.orienting_normal,
+            );
+            let nee_bsdf_pdf = brdf.pdf(&wi, &nee_result.dir, &self.orienting_normal);
+            let mis_weight = 1. / (nee_result.pdf + nee_bsdf_pdf);
+            self.rad = self.rad
+                + multiply(multiply(self.throughput, f), nee_result.color)
+                    * nee_dir_cos
+                    * mis_weight
+                    / self.roulette_pdf;
+        }
+
+        let pdf = brdf.pdf(&wi, &dir, &self.orienting_normal);
+        if pdf > 0. {
+            let f = brdf.eval(&self.record.color, &wi, &dir, &self.orienting_normal);
+            let cos_o = dot(self.orienting_normal, dir);
+            self.throughput = multiply(self.throughput, f) * cos_o / pdf;
+        } else {
+            // sampled below the surface
+            self.throughput = Vec3::new(0.);
+        }
+        self.pt_sample_pdf = pdf;
+    }
+
     fn trace_specular(&mut self, cior: &Color, k: &Color) {
         let out_dir = reflection_dir(self.orienting_normal, self.now_ray.dir);
         self.now_ray = Ray {
@@ -150,38 +653,30 @@ impl Pathtracing {
             dir: out_dir,
         };
 
-        let fresnel = if cior.0 < 0. {
-            fr_dielectric_col(&self.record.color, &out_dir, &self.orienting_normal)
-        } else {
-            fr_conductor(cior, k, &out_dir, &self.orienting_normal)
-        };
+        let fresnel = self.fresnel(cior, k, &out_dir, &self.orienting_normal);
         self.throughput = multiply(self.throughput, fresnel);
         self.pt_sample_pdf = -1.;
     }
 
-    fn trace_dielectric(&mut self, ior_mat: f64, rand: &mut XorRand, trans_id: i32) {
-        let into = self.is_into(trans_id);
+    fn trace_dielectric(&mut self, interior: Interior, rand: &mut XorRand) {
+        let trans_id = interior.trans_id;
+        let ior_mat = interior.ior.unwrap();
+        let ior_out = self.medium_stack.outer_ior(trans_id);
+        let into = self.medium_stack.is_into(trans_id);
 
-        let (is_refract, out_dir, fresnel, refl_prob) = refraction_dir(
-            into,
-            1.,
-            ior_mat,
-            self.orienting_normal,
-            self.now_ray.dir,
-            rand,
-        );
+        let (is_refract, out_dir, fresnel, refl_prob) =
+            self.sample_dielectric(into, ior_out, ior_mat, rand);
 
         let new_org;
         let nnt;
         if is_refract {
             new_org = self.record.pos - self.orienting_normal * 0.00001;
-            if into {
-                nnt = 1. / ior_mat;
-                self.medium_stack.push((trans_id, ior_mat, -1., 0.));
+            nnt = if into {
+                ior_out / ior_mat
             } else {
-                nnt = ior_mat;
-                self.remove_medium(trans_id);
-            }
+                ior_mat / ior_out
+            };
+            self.medium_stack.cross(interior);
         } else {
             new_org = self.record.pos + self.orienting_normal * 0.00001;
             nnt = 1.;
@@ -192,11 +687,31 @@ impl Pathtracing {
             dir: out_dir,
         };
 
-        self.throughput = multiply(self.throughput, self.record.color) * fresnel * nnt * nnt;
+        self.throughput =
+            multiply(multiply(self.throughput, self.record.color), fresnel) * nnt * nnt;
         self.roulette_pdf *= refl_prob;
         self.pt_sample_pdf = -1.;
     }
 
+    fn trace_thin_dielectric(&mut self, rand: &mut XorRand, ior: f64) {
+        // no refraction offset, the transmitted ray keeps its direction
+        let normal = self.orienting_normal;
+        let refl = fr_thin_dielectric(ior, &-self.now_ray.dir, &normal);
+        self.now_ray = if rand.next01() < refl {
+            Ray {
+                org: self.record.pos + normal * 0.00001,
+                dir: reflection_dir(normal, self.now_ray.dir),
+            }
+        } else {
+            Ray {
+                org: self.record.pos - normal * 0.00001,
+                dir: self.now_ray.dir,
+            }
+        };
+        self.throughput = multiply(self.throughput, self.record.color);
+        self.pt_sample_pdf = -1.;
+    }
+
     fn trace_microbrdf(
         &mut self,
         scene: &Scene,
@@ -207,51 +722,73 @@ impl Pathtracing {
         k: &Color,
     ) {
         let wi = -self.now_ray.dir;
-        let vn = sample_ggx_vndf(&self.orienting_normal, &wi, ax, ay, rand);
-        let dir = reflection_dir(vn, -wi);
-        let alpha_sq = ggx_alpha2(ax, ay, &vn, &self.orienting_normal);
-        let g1_wo = shadow_mask_fn(alpha_sq, &dir, &self.orienting_normal);
-        let fresnel = if cior.0 < 0. {
-            fr_dielectric_col(&self.record.color, &dir, &vn)
+        let normal = self.orienting_normal;
+        let tangent = self.record.tangent;
+        let alpha = (ax * ay).sqrt();
+        let cos_i = dot(wi, normal).abs();
+        let f0 = self.fresnel(cior, k, &normal, &normal);
+        // the multiple scattering lobe is sampled by cosine in proportion to its energy
+        let ms_prob = if self.config.multiscatter && alpha > 0. {
+            1. - ggx_albedo(alpha, cos_i)
         } else {
-            fr_conductor(cior, k, &dir, &vn)
+            0.
         };
 
-        let org = self.record.pos + self.orienting_normal * 0.00001;
+        let dir = if rand.next01() < ms_prob {
+            sample_lambert(&normal, rand)
+        } else {
+            reflection_dir(sample_ggx_vndf(&normal, &tangent, &wi, ax, ay, rand), -wi)
+        };
+        let org = self.record.pos + normal * 0.00001;
         self.now_ray = Ray { org, dir };
+        let nee_result = self.nee(scene, org, rand);
 
-        let (nee_result, transmittance) = scene.nee(org, rand, self.get_sigma_e());
+        let g1 = |w: &Vec3| shadow_mask_fn(ggx_alpha2(ax, ay, w, &normal, &tangent), w, &normal);
+        let g1_wi = g1(&wi);
+        let vndf = |w: &Vec3| {
+            let vn = (wi + *w).normalize();
+            g1_wi * ggx_normal_df(ax, ay, &normal, &tangent, &vn) / (4. * cos_i)
+        };
+        let pdf =
+            |w: &Vec3| (1. - ms_prob) * vndf(w) + ms_prob * fmax(dot(*w, normal), 0.) * PI_INV;
+        let eval = |w: &Vec3| {
+            //return brdf * cos
+            let vn = (wi + *w).normalize();
+            let cos_o = dot(*w, normal);
+            let single = self.fresnel(cior, k, w, &vn) * g1(w) * vndf(w);
+            if ms_prob == 0. {
+                single
+            } else if cos_o <= 0. {
+                // lost below the surface, as in the albedo table
+                Vec3::new(0.)
+            } else {
+                single + eval_ms_conductor(alpha, &f0, cos_i, cos_o) * cos_o
+            }
+        };
 
-        let g1_wi = shadow_mask_fn(alpha_sq, &wi, &self.orienting_normal);
-        let d_vn = ggx_normal_df(alpha_sq, ax, ay, &self.orienting_normal, &vn);
-        let dot_wi_n = dot(wi, self.orienting_normal).abs();
-        let vndf = g1_wi * d_vn / (4. * dot_wi_n);
+        let nee_brdf = eval(&nee_result.dir);
+        let nee_bsdf_pdf = pdf(&nee_result.dir);
+        let sample_pdf = pdf(&dir);
+        let weight = if ms_prob == 0. {
+            // the weight of VNDF sampling, exact for smooth surfaces
+            let vn = (wi + dir).normalize();
+            self.fresnel(cior, k, &dir, &vn) * g1(&dir)
+        } else {
+            eval(&dir) / sample_pdf
+        };
 
         if nee_result.pdf != 0. {
-            let nee_vn = (wi + nee_result.dir).normalize();
-            let d_nee_vn = ggx_normal_df(alpha_sq, ax, ay, &self.orienting_normal, &nee_vn);
-            let nee_vndf = g1_wi * d_nee_vn / (4. * dot_wi_n);
-
-            let g1_nee_wo = shadow_mask_fn(alpha_sq, &nee_result.dir, &self.orienting_normal);
-            let mis_weight = 1. / (nee_result.pdf + nee_vndf);
-            let nee_fresnel = if cior.0 < 0. {
-                fr_dielectric_col(&self.record.color, &nee_result.dir, &nee_vn)
-            } else {
-                fr_conductor(cior, k, &nee_result.dir, &nee_vn)
-            };
-            let brdf = nee_fresnel * nee_vndf * g1_nee_wo;
+            let mis_weight = 1. / (nee_result.pdf + nee_bsdf_pdf);
             self.rad = self.rad
-                + multiply(nee_result.color, multiply(self.throughput, brdf))
-                    * transmittance
-                    * mis_weight
+                + multiply(nee_result.color, multiply(self.throughput, nee_brdf)) * mis_weight
                     / self.roulette_pdf;
         }
 
-        self.throughput = multiply(self.throughput, fresnel * g1_wo);
+        self.throughput = multiply(self.throughput, weight);
         if ax == 0. || ay == 0. {
             self.pt_sample_pdf = -1.;
         } else {
-            self.pt_sample_pdf = vndf;
+            self.pt_sample_pdf = sample_pdf;
         }
     }
 
@@ -259,138 +796,156 @@ impl Pathtracing {
         &mut self,
         scene: &Scene,
         rand: &mut XorRand,
-        a: f64,
-        ior_mat: f64,
-        trans_id: i32,
+        ax: f64,
+        ay: f64,
+        interior: Interior,
     ) {
         let wi = -self.now_ray.dir;
-        let vn = sample_ggx_vndf(&self.orienting_normal, &wi, a, a, rand);
-        let alpha_sq = a * a;
+        let normal = self.orienting_normal;
+        let tangent = self.record.tangent;
+        let vn = sample_ggx_vndf(&normal, &tangent, &wi, ax, ay, rand);
+        let g1 = |w: &Vec3| shadow_mask_fn(ggx_alpha2(ax, ay, w, &normal, &tangent), w, &normal);
 
-        let into = self.is_into(trans_id);
+        let trans_id = interior.trans_id;
+        let ior_mat = interior.ior.unwrap();
+        let ior_out = self.medium_stack.outer_ior(trans_id);
+        let into = self.medium_stack.is_into(trans_id);
+        let (ior_i, ior_o) = if into {
+            (ior_out, ior_mat)
+        } else {
+            (ior_mat, ior_out)
+        };
 
         let (is_refract, dir, fresnel, refl_prob) =
-            refraction_dir(into, 1., ior_mat, vn, self.now_ray.dir, rand);
+            refraction_dir(into, ior_out, ior_mat, vn, self.now_ray.dir, rand);
+        let compensation = self.btdf_compensation(ax, ay, ior_mat / ior_out, into, &wi);
+        // samples on the wrong side are lost, as in the albedo table
+        let kept = if self.config.multiscatter && (dot(dir, normal) < 0.) != is_refract {
+            0.
+        } else {
+            compensation
+        };
 
-        let g1_wo = shadow_mask_fn(alpha_sq, &dir, &self.orienting_normal);
-        let g1_wi = shadow_mask_fn(alpha_sq, &wi, &self.orienting_normal);
-        let d_vn = ggx_normal_df(alpha_sq, a, a, &self.orienting_normal, &vn);
-        let dot_wi_n = dot(wi, self.orienting_normal).abs();
+        let g1_wo = g1(&dir);
+        let g1_wi = g1(&wi);
+        let d_vn = ggx_normal_df(ax, ay, &normal, &tangent, &vn);
+        let dot_wi_n = dot(wi, normal).abs();
+        let is_delta = ax == 0. || ay == 0.;
 
         if is_refract {
-            let org = self.record.pos - self.orienting_normal * 0.00001;
+            let org = self.record.pos - normal * 0.00001;
             self.now_ray = Ray { org, dir };
 
-            let ja;
-            if into {
-                ja = micro_btdf_j(1., ior_mat, &wi, &dir, &vn);
-                self.medium_stack.push((trans_id, ior_mat, -1., 0.));
-            } else {
-                ja = micro_btdf_j(ior_mat, 1., &wi, &dir, &vn);
-                self.remove_medium(trans_id);
-            };
+            let ja = micro_btdf_j(ior_i, ior_o, &wi, &dir, &vn);
+            self.medium_stack.cross(interior);
 
             let vndf = g1_wi * dot(wi, vn) * d_vn * ja / dot_wi_n;
 
-            let (nee_result, transmittance) = scene.nee(org, rand, self.get_sigma_e());
+            let nee_result = self.nee(scene, org, rand);
             if nee_result.pdf != 0. {
-                let nee_wh;
-                let ja;
-                if into {
-                    nee_wh = -(wi + nee_result.dir * ior_mat).normalize();
-                    ja = micro_btdf_j(1., ior_mat, &wi, &nee_result.dir, &nee_wh);
-                } else {
-                    nee_wh = -(wi * ior_mat + nee_result.dir).normalize();
-                    ja = micro_btdf_j(ior_mat, 1., &wi, &nee_result.dir, &nee_wh);
-                }
+                let nee_wh = -(wi * ior_i + nee_result.dir * ior_o).normalize();
+                let ja = micro_btdf_j(ior_i, ior_o, &wi, &nee_result.dir, &nee_wh);
 
-                if dot(self.orienting_normal, nee_wh) > EPS {
-                    let g1_nee_wo =
-                        shadow_mask_fn(alpha_sq, &nee_result.dir, &self.orienting_normal);
-                    let d_nee_vn = ggx_normal_df(alpha_sq, a, a, &self.orienting_normal, &nee_wh);
+                if dot(normal, nee_wh) > EPS {
+                    let g1_nee_wo = g1(&nee_result.dir);
+                    let d_nee_vn = ggx_normal_df(ax, ay, &normal, &tangent, &nee_wh);
                     let nee_vndf = g1_wi * dot(wi, nee_wh) * d_nee_vn * ja / dot_wi_n;
                     let mis_weight = 1. / (nee_result.pdf + nee_vndf);
                     let nee_fresnel =
-                        fr_dielectric_ior(into, 1., ior_mat, &nee_result.dir, &nee_wh);
+                        fr_dielectric_ior(into, ior_out, ior_mat, &nee_result.dir, &nee_wh);
                     let nee_btdf = (1. - nee_fresnel) * g1_nee_wo * nee_vndf * dot(wi, nee_wh);
                     self.rad = self.rad
                         + multiply(
                             nee_result.color,
                             multiply(self.throughput, self.record.color),
                         ) * nee_btdf
-                            * transmittance
                             * mis_weight
+                            * compensation
                             / self.roulette_pdf;
                 }
             }
 
-            self.throughput = multiply(self.throughput, self.record.color) * fresnel * g1_wo;
+            self.throughput = multiply(self.throughput, self.record.color) * fresnel * g1_wo * kept;
             self.roulette_pdf *= refl_prob;
-            self.pt_sample_pdf = if a == 0. { -1. } else { vndf };
+            self.pt_sample_pdf = if is_delta { -1. } else { vndf };
         } else {
-            let org = self.record.pos + self.orienting_normal * 0.00001;
+            let org = self.record.pos + normal * 0.00001;
             self.now_ray = Ray { org, dir };
 
             let vndf = g1_wi * d_vn / (4. * dot_wi_n);
 
-            let (nee_result, transmittance) = scene.nee(org, rand, self.get_sigma_e());
+            let nee_result = self.nee(scene, org, rand);
             if nee_result.pdf != 0. {
                 let nee_vn = (wi + nee_result.dir).normalize();
-                let d_nee_vn = ggx_normal_df(alpha_sq, a, a, &self.orienting_normal, &nee_vn);
+                let d_nee_vn = ggx_normal_df(ax, ay, &normal, &tangent, &nee_vn);
                 let nee_vndf = g1_wi * d_nee_vn / (4. * dot_wi_n);
 
-                let g1_nee_wo = shadow_mask_fn(alpha_sq, &nee_result.dir, &self.orienting_normal);
+                let g1_nee_wo = g1(&nee_result.dir);
                 let mis_weight = 1. / (nee_result.pdf + nee_vndf);
                 let nee_fresnel = fr_dielectric_col(&self.record.color, &nee_result.dir, &nee_vn);
                 let brdf = nee_fresnel * nee_vndf * g1_nee_wo;
                 self.rad = self.rad
                     + multiply(nee_result.color, multiply(self.throughput, brdf))
-                        * transmittance
                         * mis_weight
+                        * compensation
                         / self.roulette_pdf;
             }
 
-            self.throughput = multiply(self.throughput, self.record.color) * fresnel * g1_wo;
+            self.throughput = multiply(self.throughput, self.record.color) * fresnel * g1_wo * kept;
             self.roulette_pdf *= refl_prob;
-            self.pt_sample_pdf = if a == 0. { -1. } else { vndf };
+            self.pt_sample_pdf = if is_delta { -1. } else { vndf };
         }
     }
 
-    pub fn freepath_sample(&mut self, scene: &Scene, rand: &mut XorRand) -> bool {
-        let (_, _, sigma_s, sigma_e) = self.medium_stack.last().unwrap();
-        let dist = -1. * (rand.next01()).ln() / sigma_e;
+    pub fn freepath_sample(&mut self, scene: &Scene, rand: &mut XorRand) -> Option<bool> {
+        //return Some(true) when a surface is hit, None when the path ends in the medium
+        let medium = self.medium_stack.get_medium().unwrap();
+        let (dist, weight, emission) =
+            scene.sample_free_path(&self.state.now_ray, &medium, &mut self.state.record, rand);
+        self.rad = self.rad + multiply(self.throughput, emission) / self.roulette_pdf;
+        self.throughput = multiply(self.throughput, weight);
+
+        if let Some(dist) = dist {
+            if !self.next_bounce(Lobe::Volume) {
+                return None;
+            }
 
-        self.record = HitRecord::init_with_dist(dist);
-        if !scene.intersect(&self.now_ray, &mut self.record, &scene.bvh_tree[0]) {
-            self.throughput = self.throughput * *sigma_s / *sigma_e;
             let org = self.now_ray.org + self.now_ray.dir * dist;
-            let dir = sample_hg_phase(&self.now_ray.dir, 0.8, rand);
-            let hg_pdf = hg_phase_pdf(&self.now_ray.dir, &dir, 0.8);
+            let phase = medium.get_phase();
+            // no surface at a scattering event, nothing can leak
+            self.geometric_normal = Vec3::new(0.);
+            let dir = phase.sample(&self.now_ray.dir, rand);
+            let phase_pdf = phase.pdf(&self.now_ray.dir, &dir);
 
-            let (nee_result, transmittance) = scene.nee(org, rand, *sigma_e);
+            let nee_result = self.nee(scene, org, rand);
             if nee_result.pdf != 0. {
-                let nee_hg_pdf = hg_phase_pdf(&self.now_ray.dir, &nee_result.dir, 0.8);
-                let mis_weight = 1. / (nee_result.pdf + nee_hg_pdf);
+                let nee_phase_pdf = phase.pdf(&self.now_ray.dir, &nee_result.dir);
+                let mis_weight = 1. / (nee_result.pdf + nee_phase_pdf);
                 self.rad = self.rad
-                    + multiply(self.throughput, nee_result.color)
-                        * nee_hg_pdf
-                        * transmittance
-                        * mis_weight
+                    + multiply(self.throughput, nee_result.color) * nee_phase_pdf * mis_weight
                         / self.roulette_pdf;
             }
 
-            self.pt_sample_pdf = hg_pdf;
+            self.pt_sample_pdf = phase_pdf;
             self.now_ray = Ray { org, dir };
-            return false;
+            return Some(false);
         }
-        true
+        // the ray can only escape a medium without a closed boundary
+        (self.record.obj_id != -1).then_some(true)
     }
 
     pub fn integrate(&mut self, scene: &Scene, rand: &mut XorRand) -> Color {
-        for time in 0.. {
-            if self.has_medium() && !self.has_ior() {
-                if !self.freepath_sample(scene, rand) {
-                    let roulette_prob = self.roulette(time);
+        if self.config.spectral {
+            self.lambdas = Some(sample_wavelengths(rand));
+        }
+
+        loop {
+            if self.medium_stack.has_medium() {
+                let Some(hit_surface) = self.freepath_sample(scene, rand) else {
+                    break;
+                };
+                if !hit_surface {
+                    let roulette_prob = self.roulette();
                     if rand.next01() > roulette_prob {
                         break;
                     }
@@ -402,18 +957,32 @@ impl Pathtracing {
                     break;
                 }
             }
+            scene.select_mix(&mut self.record, rand);
+            self.record.color = self.to_spectrum(&self.record.color);
+            self.orient_normals();
+            if self.cross_interface(scene) {
+                continue;
+            }
+            // seen from below the shading hemisphere the bxdf is black, in both integrators
+            if self.leaks(&self.now_ray.dir) {
+                break;
+            }
 
-            let roulette_prob = self.roulette(time);
+            let roulette_prob = self.roulette();
             if rand.next01() > roulette_prob {
                 break;
             }
             self.roulette_pdf *= roulette_prob;
 
-            self.orienting_normal = if dot(self.record.normal, self.now_ray.dir) < 0. {
-                self.record.normal
-            } else {
-                -self.record.normal
-            };
+            let lobe = self.select_lobe(rand);
+            if let Some(lobe) = lobe {
+                if !self.next_bounce(lobe) {
+                    break;
+                }
+            }
+            if self.record.bxdf.is_dispersive() {
+                self.collapse_wavelengths();
+            }
 
             match self.record.bxdf {
                 Bxdf::Light => {
@@ -423,40 +992,83 @@ impl Pathtracing {
                 Bxdf::Lambertian => {
                     self.trace_lambertian(scene, rand);
                 }
-                Bxdf::Specular { cior, k } => {
-                    self.trace_specular(&cior, &k);
-                }
-                Bxdf::Dielectric { ior, trans_id } => {
-                    self.trace_dielectric(ior, rand, trans_id);
+                Bxdf::Translucent { transmission } => {
+                    self.trace_translucent(scene, rand, transmission);
                 }
-                Bxdf::MicroBrdf { ax, ay, cior, k } => {
-                    self.trace_microbrdf(scene, rand, ax, ay, &cior, &k);
+                Bxdf::ThinDielectric { ior } => {
+                    let ior = self.select_ior(&ior);
+                    self.trace_thin_dielectric(rand, ior);
                 }
-                Bxdf::MicroBtdf { a, ior, trans_id } => {
-                    self.trace_microbtdf(scene, rand, a, ior, trans_id);
+                Bxdf::OrenNayar { sigma } => {
+                    self.trace_oren_nayar(scene, rand, sigma);
                 }
-                Bxdf::Medium {
-                    sigma_a: _,
-                    sigma_s,
-                    sigma_e,
+                Bxdf::Principled {
+                    params,
+                    ior,
                     trans_id,
                 } => {
-                    if self.is_into(trans_id) {
-                        self.medium_stack.push((trans_id, 1., sigma_s, sigma_e));
-                        self.now_ray = Ray {
-                            org: self.record.pos - self.orienting_normal * 0.00001,
-                            dir: self.now_ray.dir,
-                        };
+                    if matches!(lobe, Some(Lobe::Transmission)) {
+                        let interior = self.interior(scene, trans_id, &ior);
+                        let a = params.alpha();
+                        self.trace_microbtdf(scene, rand, a, a, interior);
                     } else {
-                        self.now_ray = Ray {
-                            org: self.record.pos - self.orienting_normal * 0.00001,
-                            dir: self.now_ray.dir,
-                        };
-                        self.remove_medium(trans_id);
+                        self.trace_brdf(scene, rand, &params);
                     }
                 }
+                Bxdf::Coated { coat, base } => {
+                    let layered = self.layers(scene).layered(&coat, base);
+                    self.trace_brdf(scene, rand, &layered);
+                }
+                Bxdf::Sheen { sheen, base } => {
+                    let sheen_layer = self.layers(scene).sheen_layer(&sheen, base);
+                    self.trace_brdf(scene, rand, &sheen_layer);
+                }
+                Bxdf::Measured { brdf } => {
+                    let measured = self.layers(scene).measured(brdf);
+                    self.trace_brdf(scene, rand, &measured);
+                }
+                Bxdf::Specular { cior, k, metal, .. } => {
+                    let (cior, k) = self.conductor_ior(&cior, &k, metal);
+                    self.trace_specular(&cior, &k);
+                }
+                Bxdf::Dielectric { ior, trans_id, .. } | Bxdf::Subsurface { ior, trans_id, .. } => {
+                    let interior = self.interior(scene, trans_id, &ior);
+                    self.trace_dielectric(interior, rand);
+                }
+                Bxdf::MicroBrdf {
+                    ax,
+                    ay,
+                    cior,
+                    k,
+                    metal,
+                    ..
+                } => {
+                    let (cior, k) = self.conductor_ior(&cior, &k, metal);
+                    self.trace_microbrdf(scene, rand, ax, ay, &cior, &k);
+                }
+                Bxdf::MicroBtdf {
+                    ax,
+                    ay,
+                    ior,
+                    trans_id,
+                } => {
+                    let interior = self.interior(scene, trans_id, &ior);
+                    self.trace_microbtdf(scene, rand, ax, ay, interior);
+                }
+                // crossed by cross_interface
+                Bxdf::Medium { .. } => {}
+                // resolved by Scene::select_mix
+                Bxdf::Mix { .. } => {}
             }
+            // a bumped normal can send the path through the surface
+            if self.leaks(&self.now_ray.dir) {
+                break;
+            }
+        }
+
+        match self.lambdas {
+            Some(lambdas) => spectrum_to_rgb(&self.rad, &lambdas),
+            None => self.rad,
         }
-        self.rad
     }
 }
@@ -29,6 +29,23 @@ fn convert_to_coord(point: &LinkedHashMap<String, Property>) -> Point3 {
     Vec3(x, y, z)
 }
 
+fn convert_to_uv(point: &LinkedHashMap<String, Property>) -> Option<(f64, f64)> {
+    //return texture coordinates named u, v or s, t
+    let get = |key: &str| match point.get(key) {
+        Some(Float(x)) => Some(*x as f64),
+        _ => None,
+    };
+    get("u").zip(get("v")).or(get("s").zip(get("t")))
+}
+
+fn uv_tangent(p: &Point3, q: &Point3, r: &Point3, uvs: [(f64, f64); 3]) -> Option<Vec3> {
+    //return dp/du of the triangle, None for degenerate texture coordinates
+    let (du1, dv1) = (uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1);
+    let (du2, dv2) = (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1);
+    let det = du1 * dv2 - du2 * dv1;
+    (det.abs() > 1e-12).then(|| ((*q - *p) * dv2 - (*r - *p) * dv1) / det)
+}
+
 pub fn read_ply<'a>(
     file_path: &str,
     color: Color,
@@ -56,7 +73,17 @@ pub fn read_ply<'a>(
         let q = convert_to_coord(&points[(idx[1]) as usize]) * scale + translation;
         let r = convert_to_coord(&points[(idx[2]) as usize]) * scale + translation;
 
-        let triangle = Object::set_tri(p, q, r, bxdf, Texture::SolidTex { color: color }, freshid);
+        let mut triangle =
+            Object::set_tri(p, q, r, bxdf, Texture::SolidTex { color: color }, freshid);
+        // mesh tangents follow the texture coordinates when the vertices have them
+        let uvs: Option<Vec<(f64, f64)>> = idx[..3]
+            .iter()
+            .map(|i| convert_to_uv(&points[*i as usize]))
+            .collect();
+        if let Some(tangent) = uvs.and_then(|uvs| uv_tangent(&p, &q, &r, [uvs[0], uvs[1], uvs[2]]))
+        {
+            triangle = triangle.set_tangent(tangent);
+        }
         objects.push(triangle);
     }
 
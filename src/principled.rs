@@ -0,0 +1,149 @@
+use crate::{
+    material::{
+        dir_from_cos, fr_dielectric_col, ggx_normal_df, reflection_dir, sample_ggx_vndf,
+        sample_lambert, shadow_mask_fn, Brdf, NO_TANGENT,
+    },
+    math::{avg_elm, dot, Color, Vec3, PI},
+    random::XorRand,
+};
+
+// Burley, "Physically Based Shading at Disney", 2012
+// base color comes from the texture, the parameters are in [0, 1]. glTF metallic-roughness
+// and its extensions map directly: metallicFactor, roughnessFactor, KHR_materials_specular,
+// KHR_materials_sheen, KHR_materials_clearcoat and KHR_materials_transmission
+#[derive(Clone, Copy)]
+pub struct Principled {
+    pub metallic: f64,
+    pub roughness: f64,
+    pub specular: f64, // f0 = 0.08 * specular for dielectrics
+    pub specular_tint: f64,
+    pub sheen: f64,
+    pub sheen_tint: f64,
+    pub clearcoat: f64,
+    pub clearcoat_gloss: f64,
+    pub transmission: f64, // rough dielectric lobe, uses the ior of Bxdf::Principled
+}
+
+#[allow(unused)]
+impl Principled {
+    pub fn new() -> Self {
+        Principled {
+            metallic: 0.,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.,
+            sheen: 0.,
+            sheen_tint: 0.5,
+            clearcoat: 0.,
+            clearcoat_gloss: 1.,
+            transmission: 0.,
+        }
+    }
+
+    pub fn transmission_weight(&self) -> f64 {
+        (1. - self.metallic) * self.transmission
+    }
+
+    pub fn alpha(&self) -> f64 {
+        (self.roughness * self.roughness).max(0.001)
+    }
+
+    fn clearcoat_alpha(&self) -> f64 {
+        0.1 + (0.001 - 0.1) * self.clearcoat_gloss
+    }
+
+    fn lobe_probs(&self) -> (f64, f64, f64) {
+        //return selection probabilities of (diffuse, specular, clearcoat)
+        let diffuse = 1. - self.metallic;
+        let specular = 1.;
+        let clearcoat = 0.25 * self.clearcoat;
+        let sum = diffuse + specular + clearcoat;
+        (diffuse / sum, specular / sum, clearcoat / sum)
+    }
+}
+
+impl Brdf for Principled {
+    fn eval(&self, base: &Color, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color {
+        //return brdf of the opaque lobes, the transmission lobe is selected by the caller
+        let cos_i = dot(*wi, *normal);
+        let cos_o = dot(*wo, *normal);
+        if cos_i <= 0. || cos_o <= 0. {
+            return Vec3::new(0.);
+        }
+        let vn = (*wi + *wo).normalize();
+        let cos_d = dot(*wo, vn);
+
+        let lum = avg_elm(base);
+        let tint = if lum > 0. { *base / lum } else { Vec3::new(1.) };
+
+        // diffuse with the retro-reflection at grazing angles, and sheen
+        let fd90 = 0.5 + 2. * self.roughness * cos_d * cos_d;
+        let fd =
+            (1. + (fd90 - 1.) * schlick_weight(cos_i)) * (1. + (fd90 - 1.) * schlick_weight(cos_o));
+        let sheen_color = Vec3::new(1. - self.sheen_tint) + tint * self.sheen_tint;
+        let sheen = sheen_color * self.sheen * schlick_weight(cos_d);
+        let diffuse = (*base * (fd / PI) + sheen) * (1. - self.metallic);
+
+        let a = self.alpha();
+        let alpha_sq = a * a;
+        let d = ggx_normal_df(a, a, normal, &NO_TANGENT, &vn);
+        let g = shadow_mask_fn(alpha_sq, wi, normal) * shadow_mask_fn(alpha_sq, wo, normal);
+        let spec_color = Vec3::new(1. - self.specular_tint) + tint * self.specular_tint;
+        let f0 = spec_color * (0.08 * self.specular) * (1. - self.metallic) + *base * self.metallic;
+        let specular = fr_dielectric_col(&f0, wo, &vn) * (d * g / (4. * cos_i * cos_o));
+
+        // clearcoat, GTR1 with fixed ior 1.5 and alpha 0.25 for masking
+        let dc = gtr1(dot(*normal, vn), self.clearcoat_alpha());
+        let fc = 0.04 + 0.96 * schlick_weight(cos_d);
+        let gc = shadow_mask_fn(0.0625, wi, normal) * shadow_mask_fn(0.0625, wo, normal);
+        let clearcoat = 0.25 * self.clearcoat * dc * fc * gc / (4. * cos_i * cos_o);
+
+        diffuse + specular + Vec3::new(clearcoat)
+    }
+
+    fn sample(&self, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3 {
+        let (pd, ps, _) = self.lobe_probs();
+        let u = rand.next01();
+        if u < pd {
+            sample_lambert(normal, rand)
+        } else if u < pd + ps {
+            let a = self.alpha();
+            let vn = sample_ggx_vndf(normal, &NO_TANGENT, wi, a, a, rand);
+            reflection_dir(vn, -*wi)
+        } else {
+            let alpha_sq = self.clearcoat_alpha().powi(2);
+            let cos_h = ((1. - alpha_sq.powf(1. - rand.next01())) / (1. - alpha_sq)).sqrt();
+            let vn = dir_from_cos(normal, cos_h, rand);
+            reflection_dir(vn, -*wi)
+        }
+    }
+
+    fn pdf(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        let cos_i = dot(*wi, *normal);
+        let cos_o = dot(*wo, *normal);
+        if cos_i <= 0. || cos_o <= 0. {
+            return 0.;
+        }
+        let (pd, ps, pc) = self.lobe_probs();
+        let vn = (*wi + *wo).normalize();
+        let cos_h = dot(*normal, vn);
+
+        let a = self.alpha();
+        let alpha_sq = a * a;
+        let vndf = shadow_mask_fn(alpha_sq, wi, normal)
+            * ggx_normal_df(a, a, normal, &NO_TANGENT, &vn)
+            / (4. * cos_i);
+        let clearcoat = gtr1(cos_h, self.clearcoat_alpha()) * cos_h / (4. * dot(*wo, vn));
+
+        pd * cos_o / PI + ps * vndf + pc * clearcoat
+    }
+}
+
+fn schlick_weight(cos: f64) -> f64 {
+    (1. - cos).clamp(0., 1.).powi(5)
+}
+
+fn gtr1(cos_h: f64, a: f64) -> f64 {
+    let alpha_sq = a * a;
+    (alpha_sq - 1.) / (PI * alpha_sq.ln() * (1. + (alpha_sq - 1.) * cos_h * cos_h))
+}
@@ -9,10 +9,13 @@ pub struct Ray {
 pub struct HitRecord {
     pub pos: Point3,
     pub normal: Vec3,
+    pub shading_normal: Vec3, // perturbed by the bump or normal map, normal without one
     pub distance: f64,
     pub color: Color,
     pub bxdf: Bxdf,
     pub obj_id: i32,
+    pub uv: (f64, f64),
+    pub tangent: Vec3, // orients anisotropic bxdfs, see material::tangent_frame
 }
 
 impl HitRecord {
@@ -20,10 +23,13 @@ impl HitRecord {
         HitRecord {
             pos: Vec3::new(0.),
             normal: Vec3::new(0.),
+            shading_normal: Vec3::new(0.),
             distance: INF,
             color: Vec3::new(0.),
             bxdf: Bxdf::Light,
             obj_id: -1,
+            uv: (0., 0.),
+            tangent: Vec3::new(0.),
         }
     }
 
@@ -31,10 +37,13 @@ impl HitRecord {
         HitRecord {
             pos: Vec3::new(0.),
             normal: Vec3::new(0.),
+            shading_normal: Vec3::new(0.),
             distance: d,
             color: Vec3::new(0.),
             bxdf: Bxdf::Light,
             obj_id: -1,
+            uv: (0., 0.),
+            tangent: Vec3::new(0.),
         }
     }
 }
@@ -5,20 +5,25 @@ use rayon::prelude::*;
 
 use crate::{
     camera::Camara,
+    integrator::Integrator,
+    lighttracing::LightTracing,
     math::{clamp_vec, gamma_rev, is_valid, Color, Vec3},
-    pathtracing::Pathtracing,
+    pathtracing::PathConfig,
     random::XorRand,
     ray::Ray,
     scene::Scene,
 };
 
-pub fn render(camera: &impl Camara, scene: &Scene) {
+pub fn render(camera: &impl Camara, scene: &Scene, integrator: &Integrator, config: &PathConfig) {
     let (pixel_w, pixel_h) = camera.get_pixel();
     let (spp, sspp) = camera.get_sample();
-    let coeff = camera.get_coeff();
+    let coeff = if integrator.is_radiometric() {
+        camera.get_coeff()
+    } else {
+        1. / (spp * sspp * sspp) as f64
+    };
 
     let mut buffer = vec![Vec3::new(0.); (pixel_w * pixel_h) as usize];
-    let mut img = Image::new(pixel_w, pixel_h);
 
     buffer
         .par_chunks_mut(pixel_w as usize)
@@ -30,12 +35,14 @@ pub fn render(camera: &impl Camara, scene: &Scene) {
 
                 for sv in 0..sspp {
                     for su in 0..sspp {
-                        let (g_term, org, dir) = camera.setup(u, v as u32, su, sv, &mut rand);
+                        let (mut g_term, org, dir) = camera.setup(u, v as u32, su, sv, &mut rand);
+                        if !integrator.is_radiometric() {
+                            g_term = 1.;
+                        }
 
                         for _ in 0..spp {
-                            let mut tracer = Pathtracing::new(Ray { org, dir });
-                            let rad = tracer.integrate(scene, &mut rand) * g_term;
-                            //let rad = tracer.test_normal(scene) * g_term;
+                            let rad = integrator.trace(scene, Ray { org, dir }, config, &mut rand)
+                                * g_term;
                             if !is_valid(&rad) {
                                 continue;
                             }
@@ -61,11 +68,10 @@ pub fn render(camera: &impl Camara, scene: &Scene) {
 
                 for sv in 0..2 {
                     for su in 0..2 {
-                        let (g_term, org, dir) = camera.setup(u, v as u32, su, sv, &mut rand);
+                        let (_, org, dir) = camera.setup(u, v as u32, su, sv, &mut rand);
 
                         for _ in 0..spp {
-                            let mut tracer = Pathtracing::new(Ray { org, dir });
-                            let rad = tracer.test_normal(scene) * g_term;
+                            let rad = Integrator::Normal.trace(scene, Ray { org, dir }, config, &mut rand);
                             if !is_valid(&rad) {
                                 continue;
                             }
@@ -91,11 +97,55 @@ pub fn render(camera: &impl Camara, scene: &Scene) {
         0.1,
     );
     */
+    save_bmp(&buffer, pixel_w, pixel_h, "render.bmp");
+}
+
+#[allow(unused)]
+pub fn render_light(camera: &impl Camara, scene: &Scene, config: &PathConfig) {
+    // same number of samples as render(), traced from the lights instead of the camera
+    let (pixel_w, pixel_h) = camera.get_pixel();
+    let (spp, sspp) = camera.get_sample();
+    let paths_per_row = pixel_w * spp * sspp * sspp;
+
+    let buffer = (0..pixel_h)
+        .into_par_iter()
+        .fold(
+            || vec![Vec3::new(0.); (pixel_w * pixel_h) as usize],
+            |mut buffer, v| {
+                let mut rand = XorRand::new(v);
+                for _ in 0..paths_per_row {
+                    let mut tracer = LightTracing::new(*config);
+                    tracer.trace(scene, camera, &mut rand, &mut buffer);
+                }
+                println!("{v}");
+                buffer
+            },
+        )
+        .reduce(
+            || vec![Vec3::new(0.); (pixel_w * pixel_h) as usize],
+            |mut acc, buffer| {
+                for (a, b) in acc.iter_mut().zip(buffer.iter()) {
+                    *a = *a + *b;
+                }
+                acc
+            },
+        );
+
+    let paths = (paths_per_row * pixel_h) as f64;
+    let buffer: Vec<Color> = buffer
+        .iter()
+        .map(|col| clamp_vec(*col / paths, 0., 1.))
+        .collect();
+    save_bmp(&buffer, pixel_w, pixel_h, "render_light.bmp");
+}
+
+fn save_bmp(buffer: &[Color], pixel_w: u32, pixel_h: u32, path: &str) {
+    let mut img = Image::new(pixel_w, pixel_h);
     for i in 0..pixel_w * pixel_h {
         let y = i / pixel_w;
         let x = i - pixel_w * y;
         let rgb = gamma_rev(buffer[i as usize]);
         img.set_pixel(x, y as u32, px!(rgb.0, rgb.1, rgb.2));
     }
-    let _ = img.save("render.bmp");
+    let _ = img.save(path);
 }
@@ -1,6 +1,10 @@
 use crate::{
     bvh::{construct_bvh, BvhNode, BvhTree},
-    math::Point3,
+    material::Bxdf,
+    math::{avg_elm, exp_vec, multiply, Color, Point3, Vec3},
+    measured::MeasuredBrdf,
+    medium::{Interior, MediumStack},
+    mix::Mix,
     object::{
         sample_rect, sample_rect_pdf, sample_sphere, sample_sphere_pdf, sample_tri_pdf,
         sample_triangle, Object,
@@ -8,6 +12,7 @@ use crate::{
     random::XorRand,
     ray::{HitRecord, NeeResult, Ray},
     texture::Texture,
+    volume::Grid,
 };
 
 pub struct Scene<'a> {
@@ -16,6 +21,11 @@ pub struct Scene<'a> {
     pub background: Texture<'a>,
     pub lights: Vec<&'a Object<'a>>,
     pub bvh_tree: BvhTree,
+    pub grids: Vec<&'a Grid>,
+    pub interiors: Vec<Interior>,
+    pub materials: Vec<Bxdf>,
+    pub mixes: Vec<Mix<'a>>,
+    pub measured: Vec<&'a MeasuredBrdf>,
 }
 
 impl<'a> Scene<'a> {
@@ -36,9 +46,90 @@ impl<'a> Scene<'a> {
             background: back,
             lights,
             bvh_tree,
+            grids: Vec::new(),
+            interiors: Vec::new(),
+            materials: Vec::new(),
+            mixes: Vec::new(),
+            measured: Vec::new(),
         }
     }
 
+    #[allow(unused)]
+    pub fn set_grids(mut self, grids: Vec<&'a Grid>) -> Self {
+        // density grids referred by Bxdf::Medium { grid, .. }
+        self.grids = grids;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn set_interiors(mut self, interiors: Vec<Interior>) -> Self {
+        // priorities and interior media of dielectrics and media, referred by trans_id
+        self.interiors = interiors;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn set_materials(mut self, materials: Vec<Bxdf>) -> Self {
+        // bxdfs referred by index from layered materials, Bxdf::Coated { base, .. }
+        for (i, bxdf) in materials.iter().enumerate() {
+            assert!(
+                bxdf.is_layer_base(),
+                "material {} is not a non-delta reflective bxdf",
+                i
+            );
+            let mut next = *bxdf;
+            let mut depth = 0;
+            while let Bxdf::Coated { base, .. } | Bxdf::Sheen { base, .. } = next {
+                if base < 0 {
+                    break;
+                }
+                depth += 1;
+                assert!(
+                    depth <= materials.len(),
+                    "material {} is layered on itself",
+                    i
+                );
+                next = materials[base as usize];
+            }
+        }
+        self.materials = materials;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn set_mixes(mut self, mixes: Vec<Mix<'a>>) -> Self {
+        // blends referred by Bxdf::Mix { mix }
+        self.mixes = mixes;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn set_measured(mut self, measured: Vec<&'a MeasuredBrdf>) -> Self {
+        // tabulated brdfs referred by Bxdf::Measured { brdf }
+        self.measured = measured;
+        self
+    }
+
+    pub fn select_mix(&self, record: &mut HitRecord, rand: &mut XorRand) {
+        // replace a mix with one of its bxdfs, selected by the weights at the hit uv
+        while let Bxdf::Mix { mix } = record.bxdf {
+            let (u, v) = record.uv;
+            let entry = self.mixes[mix as usize].select(u, v, rand.next01());
+            record.bxdf = entry.bxdf;
+            if let Some(texture) = &entry.texture {
+                record.color = texture.get_color(u, v);
+            }
+        }
+    }
+
+    pub fn get_interior(&self, trans_id: i32) -> Interior {
+        self.interiors
+            .iter()
+            .find(|i| i.trans_id == trans_id)
+            .copied()
+            .unwrap_or(Interior::new(trans_id, 0, None))
+    }
+
     pub fn intersect_obj(&self, ray: &Ray, record: &mut HitRecord, node: &BvhNode) -> bool {
         let (l, r) = node.children;
         if node.bbox.hit(ray, record) {
@@ -54,6 +145,24 @@ impl<'a> Scene<'a> {
         record.obj_id != -1
     }
 
+    pub fn traversal_cost(&self, ray: &Ray, record: &mut HitRecord, node: &BvhNode) -> u32 {
+        // number of visited nodes and tested objects, same traversal as intersect_obj
+        let (l, r) = node.children;
+        let mut cost = 1;
+        if node.bbox.hit(ray, record) {
+            if l == -1 {
+                for i in node.elements.iter() {
+                    let _ = self.objects[*i].hit(ray, record);
+                    cost += 1;
+                }
+            } else {
+                cost += self.traversal_cost(ray, record, &self.bvh_tree[l as usize]);
+                cost += self.traversal_cost(ray, record, &self.bvh_tree[r as usize]);
+            }
+        }
+        cost
+    }
+
     fn intersect_medium(&self, ray: &Ray, record: &mut HitRecord) -> bool {
         for med in self.mediums.iter() {
             med.hit(ray, record);
@@ -68,32 +177,138 @@ impl<'a> Scene<'a> {
         b1 || b2
     }
 
-    fn calc_transmittance(&self, ray: &Ray, init_e: f64, max_dist: f64) -> f64 {
-        // return (sigma_extinct, distant)
-        let mut mlist = vec![(init_e, 0.)];
-        for med in self.mediums.iter() {
-            let mut record = HitRecord::init_with_dist(max_dist);
-            if med.hit(ray, &mut record) {
-                let sigma_e = med.get_bxdf().get_sigma_ex();
-                if mlist.last().unwrap().0 == sigma_e {
-                    mlist.push((0., record.distance));
-                } else {
-                    mlist.push((sigma_e, record.distance));
-                }
+    pub fn sample_free_path(
+        &self,
+        ray: &Ray,
+        medium: &Bxdf,
+        record: &mut HitRecord,
+        rand: &mut XorRand,
+    ) -> (Option<f64>, Color, Color) {
+        //return (distance of a collision in medium, throughput weight, emitted radiance),
+        //record is the surface hit when there is no collision
+        let Bxdf::Medium {
+            sigma_a,
+            sigma_s,
+            sigma_e,
+            grid,
+            emission,
+            emission_grid,
+            ..
+        } = *medium
+        else {
+            return (None, Vec3::new(1.), Vec3::new(0.));
+        };
+        let emission_at = |pos: Point3| {
+            if emission_grid >= 0 {
+                emission * self.grids[emission_grid as usize].density(pos)
+            } else {
+                emission
             }
+        };
+
+        if grid >= 0 {
+            *record = HitRecord::new();
+            let _ = self.intersect(ray, record, &self.bvh_tree[0]);
+            return self.grids[grid as usize].sample_distance(
+                &sigma_a,
+                &sigma_s,
+                ray,
+                record.distance,
+                emission_at,
+                rand,
+            );
         }
-        mlist.push((0., max_dist));
-        mlist.sort_by(|(_, d1), (_, d2)| d1.total_cmp(d2));
 
-        let mut transmittance = 1.;
-        for i in 0..mlist.len() - 1 {
-            transmittance *= (-mlist[i].0 * (mlist[i + 1].1 - mlist[i].1)).exp();
+        // one channel is sampled, the pdf is the average over the channels (spectral MIS)
+        let channel = [sigma_e.0, sigma_e.1, sigma_e.2][(rand.nexti() % 3) as usize];
+        let dist = -(rand.next01()).ln() / channel;
+        *record = HitRecord::init_with_dist(dist);
+        if self.intersect(ray, record, &self.bvh_tree[0]) {
+            let transmittance = exp_vec(sigma_e * -record.distance);
+            (None, transmittance / avg_elm(&transmittance), Vec3::new(0.))
+        } else {
+            let transmittance = exp_vec(sigma_e * -dist);
+            let pdf = avg_elm(&multiply(sigma_e, transmittance));
+            let pos = ray.org + ray.dir * dist;
+            (
+                Some(dist),
+                multiply(sigma_s, transmittance) / pdf,
+                multiply(multiply(sigma_a, emission_at(pos)), transmittance) / pdf,
+            )
+        }
+    }
+
+    fn medium_transmittance(
+        &self,
+        medium: Option<Bxdf>,
+        ray: &Ray,
+        t0: f64,
+        t1: f64,
+        rand: &mut XorRand,
+    ) -> Color {
+        match medium {
+            Some(Bxdf::Medium { sigma_e, grid, .. }) if grid >= 0 => {
+                self.grids[grid as usize].transmittance(&sigma_e, ray, t0, t1, rand)
+            }
+            Some(Bxdf::Medium { sigma_e, .. }) => exp_vec(sigma_e * -(t1 - t0)),
+            _ => Vec3::new(1.),
+        }
+    }
+
+    pub fn calc_transmittance(
+        &self,
+        ray: &Ray,
+        medium_stack: &MediumStack,
+        max_dist: f64,
+        lambdas: Option<Vec3>,
+        rand: &mut XorRand,
+    ) -> Color {
+        // walk the medium boundaries on the ray with the same stack as the integrator
+        let mut medium_stack = medium_stack.clone();
+        let mut transmittance = Vec3::new(1.);
+        let mut t = 0.;
+        loop {
+            let seg = Ray {
+                org: ray.org + ray.dir * t,
+                dir: ray.dir,
+            };
+            let mut record = HitRecord::init_with_dist(max_dist - t);
+            let is_hit = self.intersect_medium(&seg, &mut record);
+            let t_next = if is_hit {
+                t + record.distance
+            } else {
+                max_dist
+            };
+
+            let tr = self.medium_transmittance(medium_stack.get_medium(), ray, t, t_next, rand);
+            transmittance = multiply(transmittance, tr);
+            if !is_hit {
+                break;
+            }
+
+            if let Bxdf::Medium { trans_id, .. } = record.bxdf {
+                let medium = match lambdas {
+                    Some(lambdas) => record.bxdf.get_spectral_medium(&lambdas),
+                    None => record.bxdf,
+                };
+                medium_stack.cross(Interior {
+                    medium: Some(medium),
+                    ..self.get_interior(trans_id)
+                });
+            }
+            t = t_next + 0.00001;
         }
 
         transmittance
     }
 
-    pub fn nee(&self, org: Point3, rand: &mut XorRand, sigma_e: f64) -> (NeeResult, f64) {
+    pub fn nee(
+        &self,
+        org: Point3,
+        rand: &mut XorRand,
+        medium_stack: &MediumStack,
+        lambdas: Option<Vec3>,
+    ) -> (NeeResult, Color) {
         let mut nee_result = NeeResult::new();
         let mut size = self.lights.len() as u32;
 
@@ -102,7 +317,7 @@ impl<'a> Scene<'a> {
         }
 
         if size == 0 {
-            return (nee_result, 1.);
+            return (nee_result, Vec3::new(1.));
         }
 
         let idx = rand.nexti() % size;
@@ -120,13 +335,13 @@ impl<'a> Scene<'a> {
                     .background
                     .sample_hdr(cdf, &cdf_row, *px_w, *px_h, rand);
                 if self.intersect_obj(&Ray { org, dir }, &mut HitRecord::new(), &self.bvh_tree[0]) {
-                    return (nee_result, 1.);
+                    return (nee_result, Vec3::new(1.));
                 }
 
                 nee_result.color = color;
                 nee_result.pdf = pdf / size as f64;
                 nee_result.dir = dir;
-                return (nee_result, 1.);
+                return (nee_result, Vec3::new(1.));
             }
         }
 
@@ -146,10 +361,10 @@ impl<'a> Scene<'a> {
         let ray = Ray { org, dir };
         let _ = self.intersect_obj(&ray, &mut record, &self.bvh_tree[0]);
         if record.obj_id != obj.get_obj_id() {
-            return (nee_result, 1.);
+            return (nee_result, Vec3::new(1.));
         }
 
-        let transmittance = self.calc_transmittance(&ray, sigma_e, dist);
+        let transmittance = self.calc_transmittance(&ray, medium_stack, dist, lambdas, rand);
 
         nee_result.dir = dir;
         nee_result.color = record.color;
@@ -158,6 +373,19 @@ impl<'a> Scene<'a> {
         (nee_result, transmittance)
     }
 
+    pub fn sample_emission(&self, rand: &mut XorRand) -> Option<(Point3, Vec3, Color, f64)> {
+        //return (position, normal, emission, pdf_area)
+        if self.lights.is_empty() {
+            return None;
+        }
+
+        let size = self.lights.len() as u32;
+        let obj = self.lights[(rand.nexti() % size) as usize];
+        let (pos, normal, color) = obj.sample_surface(rand);
+
+        Some((pos, normal, color, 1. / (obj.get_area() * size as f64)))
+    }
+
     pub fn sample_obj_pdf(&self, org: Point3, record: &HitRecord) -> f64 {
         let obj = self.objects[record.obj_id as usize];
         match obj {
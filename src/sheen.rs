@@ -0,0 +1,153 @@
+use std::sync::OnceLock;
+
+use crate::{
+    material::{sample_lambert, sample_lambert_pdf, Brdf},
+    math::{avg_elm, dot, Color, Vec3, PI},
+    random::XorRand,
+};
+
+// retro-reflective lobe of fabrics, Charlie sheen with the visibility of Neubelt and Pettineo
+// Estevez and Kulla, "Production Friendly Microfacet Sheen BRDF", 2017
+#[derive(Clone, Copy)]
+pub struct Sheen {
+    pub roughness: f64,
+    pub color: Option<Color>, // None takes the texture of the object
+}
+
+#[allow(unused)]
+impl Sheen {
+    pub fn new(roughness: f64) -> Self {
+        Sheen {
+            roughness,
+            color: None,
+        }
+    }
+
+    pub fn set_color(mut self, color: Color) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    fn alpha(&self) -> f64 {
+        (self.roughness * self.roughness).max(0.001)
+    }
+
+    fn eval_lobe(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        //return brdf of the white sheen
+        eval_charlie(self.alpha(), wi, wo, normal)
+    }
+
+    fn albedo(&self, cos: f64) -> f64 {
+        //return directional albedo of the white sheen, interpolated from the table
+        let table = ALBEDO_TABLE.get_or_init(make_albedo_table);
+        let x = cos.clamp(0., 1.) * (ALBEDO_RES - 1) as f64;
+        let y = self.roughness.clamp(0., 1.) * (ALBEDO_RES - 1) as f64;
+        let (i, j) = (
+            (x as usize).min(ALBEDO_RES - 2),
+            (y as usize).min(ALBEDO_RES - 2),
+        );
+        let (tx, ty) = (x - i as f64, y - j as f64);
+        let at = |i: usize, j: usize| table[j * ALBEDO_RES + i];
+        (at(i, j) * (1. - tx) + at(i + 1, j) * tx) * (1. - ty)
+            + (at(i, j + 1) * (1. - tx) + at(i + 1, j + 1) * tx) * ty
+    }
+}
+
+const ALBEDO_RES: usize = 32;
+static ALBEDO_TABLE: OnceLock<Vec<f64>> = OnceLock::new();
+
+fn eval_charlie(alpha: f64, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+    let cos_i = dot(*wi, *normal);
+    let cos_o = dot(*wo, *normal);
+    if cos_i <= 0. || cos_o <= 0. {
+        return 0.;
+    }
+    let cos_h = dot((*wi + *wo).normalize(), *normal);
+    let sin_h_sq = (1. - cos_h * cos_h).max(0.);
+    let d = (2. + 1. / alpha) * sin_h_sq.powf(0.5 / alpha) / (2. * PI);
+    let v = 1. / (4. * (cos_i + cos_o - cos_i * cos_o));
+    d * v
+}
+
+fn make_albedo_table() -> Vec<f64> {
+    // midpoint quadrature over the hemisphere, rows are roughness and columns are cos
+    let (n_cos, n_phi) = (64, 32);
+    let normal = Vec3(0., 0., 1.);
+    let mut table = vec![0.; ALBEDO_RES * ALBEDO_RES];
+    for j in 0..ALBEDO_RES {
+        let roughness = j as f64 / (ALBEDO_RES - 1) as f64;
+        let alpha = (roughness * roughness).max(0.001);
+        for i in 0..ALBEDO_RES {
+            let cos_i = (i as f64 / (ALBEDO_RES - 1) as f64).max(0.001);
+            let wi = Vec3((1. - cos_i * cos_i).sqrt(), 0., cos_i);
+            let mut sum = 0.;
+            for c in 0..n_cos {
+                let cos_o = (c as f64 + 0.5) / n_cos as f64;
+                let sin_o = (1. - cos_o * cos_o).sqrt();
+                for p in 0..n_phi {
+                    let phi = PI * (p as f64 + 0.5) / n_phi as f64;
+                    let wo = Vec3(sin_o * phi.cos(), sin_o * phi.sin(), cos_o);
+                    sum += eval_charlie(alpha, &wi, &wo, &normal) * cos_o;
+                }
+            }
+            // symmetric in phi, the half hemisphere is doubled
+            table[j * ALBEDO_RES + i] = sum * 2. * PI / (n_cos * n_phi) as f64;
+        }
+    }
+    table
+}
+
+// sheen alone or layered over a base bxdf, the base is scaled down by the albedo of the sheen
+pub struct SheenLayer<B> {
+    pub sheen: Sheen,
+    pub base: Option<B>,
+}
+
+impl<B> SheenLayer<B> {
+    fn sheen_prob(&self, wi: &Vec3, normal: &Vec3) -> f64 {
+        // the texture is unknown when sampling, it counts as white
+        let lum = self.sheen.color.map_or(1., |c| avg_elm(&c));
+        (lum * self.sheen.albedo(dot(*wi, *normal))).clamp(0.1, 0.9)
+    }
+}
+
+impl<B: Brdf> Brdf for SheenLayer<B> {
+    fn eval(&self, color: &Color, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> Color {
+        let sheen_color = self.sheen.color.unwrap_or(*color);
+        let sheen = sheen_color * self.sheen.eval_lobe(wi, wo, normal);
+        let Some(base) = &self.base else {
+            return sheen;
+        };
+
+        let albedo = self
+            .sheen
+            .albedo(dot(*wi, *normal))
+            .max(self.sheen.albedo(dot(*wo, *normal)));
+        let scale = (1. - avg_elm(&sheen_color) * albedo).max(0.);
+        sheen + base.eval(color, wi, wo, normal) * scale
+    }
+
+    fn sample(&self, wi: &Vec3, normal: &Vec3, rand: &mut XorRand) -> Vec3 {
+        // the sheen lobe is broad, cosine weighted sampling is used for it
+        match &self.base {
+            Some(base) if rand.next01() >= self.sheen_prob(wi, normal) => {
+                base.sample(wi, normal, rand)
+            }
+            _ => sample_lambert(normal, rand),
+        }
+    }
+
+    fn pdf(&self, wi: &Vec3, wo: &Vec3, normal: &Vec3) -> f64 {
+        if dot(*wi, *normal) <= 0. || dot(*wo, *normal) <= 0. {
+            return 0.;
+        }
+        let sheen = sample_lambert_pdf(wo, normal);
+        match &self.base {
+            Some(base) => {
+                let prob = self.sheen_prob(wi, normal);
+                prob * sheen + (1. - prob) * base.pdf(wi, wo, normal)
+            }
+            None => sheen,
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{
+    math::{Color, Vec3},
+    random::XorRand,
+};
+
+// hero wavelength sampling, three wavelengths per path stored in a Vec3 (hero in .0)
+pub const LAMBDA_MIN: f64 = 380.;
+pub const LAMBDA_MAX: f64 = 720.;
+const LAMBDA_RANGE: f64 = LAMBDA_MAX - LAMBDA_MIN;
+// Fraunhofer d line, dispersive materials use their ior at this wavelength in rgb mode
+pub const LAMBDA_D: f64 = 587.56;
+
+// rgb of the constant spectrum 1 over [LAMBDA_MIN, LAMBDA_MAX], used as white balance
+const WHITE_RGB: Color = Vec3(128.3607, 101.5381, 97.0509);
+
+// Brian Smits, "An RGB to Spectrum Conversion for Reflectances", 1999
+// 10 bins between LAMBDA_MIN and LAMBDA_MAX
+const SMITS_WHITE: [f64; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+// wavelengths used for the r, g, b channels of per-channel material data
+pub const LAMBDA_RGB: [f64; 3] = [650., 550., 450.];
+
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Metal {
+    Au,
+    Ag,
+    Cu,
+    Al,
+}
+
+// (wavelength[nm], n, k), approximated from Johnson & Christy (Au, Ag, Cu) and Rakic (Al)
+const AU_NK: [(f64, f64, f64); 7] = [
+    (400., 1.470, 1.950),
+    (450., 1.332, 1.869),
+    (500., 0.970, 1.870),
+    (550., 0.543, 2.231),
+    (600., 0.250, 2.980),
+    (650., 0.188, 3.403),
+    (700., 0.160, 3.950),
+];
+const AG_NK: [(f64, f64, f64); 7] = [
+    (400., 0.050, 2.100),
+    (450., 0.040, 2.650),
+    (500., 0.050, 3.100),
+    (550., 0.060, 3.600),
+    (600., 0.060, 4.000),
+    (650., 0.050, 4.400),
+    (700., 0.040, 4.800),
+];
+const CU_NK: [(f64, f64, f64); 7] = [
+    (400., 1.180, 2.210),
+    (450., 1.247, 2.456),
+    (500., 1.130, 2.570),
+    (550., 1.116, 2.596),
+    (600., 0.330, 2.950),
+    (650., 0.275, 3.373),
+    (700., 0.220, 4.050),
+];
+const AL_NK: [(f64, f64, f64); 7] = [
+    (400., 0.490, 4.860),
+    (450., 0.620, 5.470),
+    (500., 0.770, 6.080),
+    (550., 0.960, 6.690),
+    (600., 1.200, 7.260),
+    (650., 1.470, 7.790),
+    (700., 1.830, 8.310),
+];
+
+impl Metal {
+    fn table(&self) -> &'static [(f64, f64, f64)] {
+        match self {
+            Metal::Au => &AU_NK,
+            Metal::Ag => &AG_NK,
+            Metal::Cu => &CU_NK,
+            Metal::Al => &AL_NK,
+        }
+    }
+
+    pub fn nk(&self, lambda: f64) -> (f64, f64) {
+        let table = self.table();
+        let (first, last) = (table[0], table[table.len() - 1]);
+        if lambda <= first.0 {
+            return (first.1, first.2);
+        } else if lambda >= last.0 {
+            return (last.1, last.2);
+        }
+
+        let i = table.iter().position(|(l, ..)| *l > lambda).unwrap();
+        let (l0, n0, k0) = table[i - 1];
+        let (l1, n1, k1) = table[i];
+        let t = (lambda - l0) / (l1 - l0);
+        (n0 + (n1 - n0) * t, k0 + (k1 - k0) * t)
+    }
+
+    pub fn rgb_nk(&self) -> (Color, Color) {
+        //return (cior, k) for the rgb renderer
+        let (rn, rk) = self.nk(LAMBDA_RGB[0]);
+        let (gn, gk) = self.nk(LAMBDA_RGB[1]);
+        let (bn, bk) = self.nk(LAMBDA_RGB[2]);
+        (Vec3(rn, gn, bn), Vec3(rk, gk, bk))
+    }
+}
+
+pub fn sample_wavelengths(rand: &mut XorRand) -> Vec3 {
+    let hero = rand.next01() * LAMBDA_RANGE;
+    let rotate = |i: f64| LAMBDA_MIN + (hero + i * LAMBDA_RANGE / 3.) % LAMBDA_RANGE;
+    Vec3(rotate(0.), rotate(1.), rotate(2.))
+}
+
+fn lobe(x: f64, mu: f64, sigma1: f64, sigma2: f64) -> f64 {
+    let t = (x - mu) / if x < mu { sigma1 } else { sigma2 };
+    (-0.5 * t * t).exp()
+}
+
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    // Wyman, Sloan and Shirley, "Simple Analytic Approximations to the CIE XYZ
+    // Color Matching Functions", 2013
+    let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+    Vec3(x, y, z)
+}
+
+pub fn xyz_to_rgb(xyz: &Vec3) -> Color {
+    // linear sRGB, D65
+    Vec3(
+        3.2406 * xyz.0 - 1.5372 * xyz.1 - 0.4986 * xyz.2,
+        -0.9689 * xyz.0 + 1.8758 * xyz.1 + 0.0415 * xyz.2,
+        0.0557 * xyz.0 - 0.2040 * xyz.1 + 1.0570 * xyz.2,
+    )
+}
+
+fn smits(rgb: &Color, lambda: f64) -> f64 {
+    let bin = (((lambda - LAMBDA_MIN) / LAMBDA_RANGE * 10.) as usize).min(9);
+    let Vec3(r, g, b) = *rgb;
+
+    if r <= g && r <= b {
+        r * SMITS_WHITE[bin]
+            + if g <= b {
+                (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+            } else {
+                (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+            }
+    } else if g <= r && g <= b {
+        g * SMITS_WHITE[bin]
+            + if r <= b {
+                (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+            } else {
+                (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+            }
+    } else {
+        b * SMITS_WHITE[bin]
+            + if r <= g {
+                (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+            } else {
+                (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+            }
+    }
+}
+
+pub fn rgb_to_spectrum(rgb: &Color, lambdas: &Vec3) -> Vec3 {
+    //return the upsampled spectrum at the three wavelengths, used for albedo and emission
+    Vec3(
+        smits(rgb, lambdas.0),
+        smits(rgb, lambdas.1),
+        smits(rgb, lambdas.2),
+    )
+}
+
+pub fn spectrum_to_rgb(values: &Vec3, lambdas: &Vec3) -> Color {
+    //values: radiance at the three wavelengths sampled by sample_wavelengths
+    let xyz = cie_xyz(lambdas.0) * values.0
+        + cie_xyz(lambdas.1) * values.1
+        + cie_xyz(lambdas.2) * values.2;
+    let rgb = xyz_to_rgb(&xyz) * (LAMBDA_RANGE / 3.);
+
+    // out of gamut samples are clipped
+    Vec3(
+        (rgb.0 / WHITE_RGB.0).max(0.),
+        (rgb.1 / WHITE_RGB.1).max(0.),
+        (rgb.2 / WHITE_RGB.2).max(0.),
+    )
+}
+
+pub fn conductor_spectrum(
+    cior: &Color,
+    k: &Color,
+    metal: Option<Metal>,
+    lambdas: &Vec3,
+) -> (Vec3, Vec3) {
+    //return (cior, k) at the three wavelengths, per-channel data is interpolated linearly
+    let eval = |lambda: f64| match metal {
+        Some(metal) => metal.nk(lambda),
+        None => {
+            let t = ((lambda - LAMBDA_RGB[2]) / (LAMBDA_RGB[0] - LAMBDA_RGB[2])).clamp(0., 1.);
+            if t < 0.5 {
+                let t = t * 2.;
+                (cior.2 + (cior.1 - cior.2) * t, k.2 + (k.1 - k.2) * t)
+            } else {
+                let t = t * 2. - 1.;
+                (cior.1 + (cior.0 - cior.1) * t, k.1 + (k.0 - k.1) * t)
+            }
+        }
+    };
+
+    let (n0, k0) = eval(lambdas.0);
+    let (n1, k1) = eval(lambdas.1);
+    let (n2, k2) = eval(lambdas.2);
+    (Vec3(n0, n1, n2), Vec3(k0, k1, k2))
+}
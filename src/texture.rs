@@ -1,4 +1,4 @@
-use std::fs::File;
+use std::{fs::File, path::Path};
 
 use crate::{
     math::{Color, Vec3, PI},
@@ -21,6 +21,69 @@ pub enum Texture<'a> {
         px_w: usize,
         px_h: usize,
     },
+    // image on an object, looked up at the hit uv without a sampling cdf
+    ImageMapTex {
+        data: &'a Vec<Color>,
+        px_w: usize,
+        px_h: usize,
+        wrap: Wrap,
+        tiling: (f64, f64), // scale of the uv, the image repeats or clamps beyond 1
+    },
+}
+
+// addressing of the uv outside [0, 1)
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+// transfer function of the stored values, decoded to linear radiance or reflectance
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum ColorSpace {
+    Srgb,
+    Gamma(f64),
+    Linear, // data maps: roughness, normal, opacity, and most hdr files
+}
+
+#[allow(unused)]
+impl Wrap {
+    fn index(&self, x: f64, px: usize) -> usize {
+        //return the pixel of the coordinate x along an axis of px pixels
+        let x = match self {
+            Wrap::Repeat => x - x.floor(),
+            Wrap::Clamp => x.clamp(0., 1.),
+            Wrap::Mirror => {
+                let t = x.rem_euclid(2.);
+                if t > 1. {
+                    2. - t
+                } else {
+                    t
+                }
+            }
+        };
+        ((px as f64 * x) as usize).min(px - 1)
+    }
+}
+
+#[allow(unused)]
+impl ColorSpace {
+    fn decode(&self, x: f64) -> f64 {
+        match self {
+            ColorSpace::Srgb => {
+                if x <= 0.04045 {
+                    x / 12.92
+                } else {
+                    ((x + 0.055) / 1.055).powf(2.4)
+                }
+            }
+            ColorSpace::Gamma(gamma) => x.max(0.).powf(*gamma),
+            ColorSpace::Linear => x,
+        }
+    }
 }
 
 impl<'a> Texture<'a> {
@@ -59,6 +122,24 @@ impl<'a> Texture<'a> {
         }
     }
 
+    #[allow(unused)]
+    pub fn set_image_map(
+        data: &'a Vec<Color>,
+        px_w: usize,
+        px_h: usize,
+        wrap: Wrap,
+        tiling: (f64, f64),
+    ) -> Self {
+        //data: loaded by load_image, rows from the top at v = 0
+        Texture::ImageMapTex {
+            data,
+            px_w,
+            px_h,
+            wrap,
+            tiling,
+        }
+    }
+
     pub fn get_color(&self, u: f64, v: f64) -> Color {
         match *self {
             Texture::SolidTex { color } => color,
@@ -79,6 +160,13 @@ impl<'a> Texture<'a> {
                 let id = id_v * px_w + id_u;
                 data[id]
             }
+            Texture::ImageMapTex {
+                data,
+                px_w,
+                px_h,
+                wrap,
+                tiling: (su, sv),
+            } => data[wrap.index(v * sv, px_h) * px_w + wrap.index(u * su, px_w)],
         }
     }
 
@@ -138,6 +226,76 @@ pub fn load_hdr(path: &str) -> (Vec<Color>, usize, usize) {
     (data, image.width, image.height)
 }
 
+#[allow(unused)]
+pub fn load_image(path: &str, color_space: ColorSpace) -> (Vec<Color>, usize, usize) {
+    //path: png, bmp or hdr by the extension, alpha channels are dropped. panics on any other
+    //extension and on unreadable files, as load_hdr does
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+
+    let (raw, px_w, px_h) = match ext.as_deref() {
+        Some("png") => {
+            let file = File::open(path).expect("failed to open png");
+            let mut decoder = png::Decoder::new(file);
+            decoder.set_transformations(png::Transformations::normalize_to_color8());
+            let mut reader = decoder.read_info().expect("failed to load png");
+            let mut buf = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut buf).expect("failed to load png");
+            let samples = info.color_type.samples();
+            let raw = buf[..info.buffer_size()]
+                .chunks(samples)
+                .map(|p| {
+                    let c = |i: usize| p[i] as f64 / 255.;
+                    if samples < 3 {
+                        Vec3::new(c(0))
+                    } else {
+                        Vec3(c(0), c(1), c(2))
+                    }
+                })
+                .collect();
+            (raw, info.width as usize, info.height as usize)
+        }
+        Some("bmp") => {
+            let image = bmp::open(path).expect("failed to load bmp");
+            let (w, h) = (image.get_width(), image.get_height());
+            let mut raw = Vec::new();
+            for y in 0..h {
+                for x in 0..w {
+                    let p = image.get_pixel(x, y);
+                    raw.push(Vec3(p.r as f64, p.g as f64, p.b as f64) / 255.);
+                }
+            }
+            (raw, w as usize, h as usize)
+        }
+        Some("hdr") => {
+            let file = File::open(path).expect("failed to open hdr");
+            let image = hdrldr::load(file).expect("failed to load hdr");
+            let raw = image
+                .data
+                .iter()
+                .map(|rgb| Vec3(rgb.r as f64, rgb.g as f64, rgb.b as f64))
+                .collect();
+            (raw, image.width, image.height)
+        }
+        _ => panic!("unsupported image format: {}", path),
+    };
+
+    let data = raw
+        .iter()
+        .map(|c: &Color| {
+            Vec3(
+                color_space.decode(c.0),
+                color_space.decode(c.1),
+                color_space.decode(c.2),
+            )
+        })
+        .collect();
+
+    (data, px_w, px_h)
+}
+
 pub fn make_cdf_hdr(hdr: &Vec<Color>, px_w: usize, px_h: usize) -> Vec<Vec<f64>> {
     let mut cdf = vec![vec![0.; px_w]; px_h];
 
@@ -217,3 +375,35 @@ pub fn sample_hdr_pdf(
 
     pdf_u * pdf_v / (2. * PI.powi(2) * (v * PI).sin())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_index_at_the_borders() {
+        let px = 4;
+        assert_eq!(Wrap::Repeat.index(-0.1, px), 3);
+        assert_eq!(Wrap::Repeat.index(1.0, px), 0);
+        assert_eq!(Wrap::Clamp.index(1.0, px), 3);
+        assert_eq!(Wrap::Clamp.index(-0.5, px), 0);
+        // mirrored about 0 and 1, so -0.1 reads 0.1 and 1.1 reads 0.9
+        assert_eq!(Wrap::Mirror.index(-0.1, px), 0);
+        assert_eq!(Wrap::Mirror.index(-0.9, px), 3);
+        assert_eq!(Wrap::Mirror.index(1.1, px), 3);
+        assert_eq!(Wrap::Mirror.index(2.1, px), 0);
+    }
+
+    #[test]
+    fn srgb_decode_is_continuous_at_the_knee() {
+        let knee = 0.04045;
+        let below = ColorSpace::Srgb.decode(knee);
+        let above = ColorSpace::Srgb.decode(knee + 1e-9);
+        assert!((below - knee / 12.92).abs() < 1e-12);
+        assert!((above - below).abs() < 1e-6);
+        assert!((ColorSpace::Srgb.decode(1.) - 1.).abs() < 1e-12);
+        assert!((ColorSpace::Srgb.decode(0.5) - 0.214).abs() < 1e-3);
+        assert_eq!(ColorSpace::Gamma(2.2).decode(-0.1), 0.);
+        assert_eq!(ColorSpace::Linear.decode(0.3), 0.3);
+    }
+}
@@ -0,0 +1,331 @@
+use std::{fs::File, io::Read};
+
+use crate::{
+    math::{avg_elm, clamp_vec, max_elm, multiply, Color, Point3, Vec3, INF},
+    random::XorRand,
+    ray::Ray,
+};
+
+const BLOCK: usize = 8; // voxels per majorant cell along each axis
+
+// density grid over the box [min, max], the coefficients of a medium are scaled by it
+pub struct Grid {
+    data: Vec<f64>,
+    res: (usize, usize, usize),
+    min: Point3,
+    max: Point3,
+    majorants: Vec<f64>,
+    maj_res: (usize, usize, usize),
+}
+
+#[allow(unused)]
+impl Grid {
+    pub fn new(data: Vec<f64>, res: (usize, usize, usize), min: Point3, max: Point3) -> Self {
+        let (nx, ny, nz) = res;
+        let maj_res = (nx.div_ceil(BLOCK), ny.div_ceil(BLOCK), nz.div_ceil(BLOCK));
+        let mut majorants = vec![0.; maj_res.0 * maj_res.1 * maj_res.2];
+
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let d = data[(z * ny + y) * nx + x];
+                    // trilinear lookups reach one voxel into the neighbouring cells
+                    for mz in (z.saturating_sub(1) / BLOCK)..=((z + 1).min(nz - 1) / BLOCK) {
+                        for my in (y.saturating_sub(1) / BLOCK)..=((y + 1).min(ny - 1) / BLOCK) {
+                            for mx in (x.saturating_sub(1) / BLOCK)..=((x + 1).min(nx - 1) / BLOCK)
+                            {
+                                let id = (mz * maj_res.1 + my) * maj_res.0 + mx;
+                                if majorants[id] < d {
+                                    majorants[id] = d;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Grid {
+            data,
+            res,
+            min,
+            max,
+            majorants,
+            maj_res,
+        }
+    }
+
+    pub fn from_fn(
+        res: (usize, usize, usize),
+        min: Point3,
+        max: Point3,
+        f: impl Fn(Point3) -> f64,
+    ) -> Self {
+        //f: density at the local position in [0, 1]^3
+        let (nx, ny, nz) = res;
+        let mut data = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let local = Vec3(
+                        (x as f64 + 0.5) / nx as f64,
+                        (y as f64 + 0.5) / ny as f64,
+                        (z as f64 + 0.5) / nz as f64,
+                    );
+                    data.push(f(local).max(0.));
+                }
+            }
+        }
+        Grid::new(data, res, min, max)
+    }
+
+    pub fn load_raw(path: &str, min: Point3, max: Point3) -> Self {
+        // nx, ny, nz as u32 followed by nx * ny * nz f32 (x fastest), little endian
+        let mut file = File::open(path).expect("failed to open grid");
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).expect("failed to read grid");
+
+        let read_u32 = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()) as usize;
+        let res = (read_u32(0), read_u32(4), read_u32(8));
+        let size = res.0 * res.1 * res.2;
+        assert!(bytes.len() >= 12 + size * 4, "grid file is too short");
+
+        let data = bytes[12..12 + size * 4]
+            .chunks_exact(4)
+            .map(|b| (f32::from_le_bytes(b.try_into().unwrap()) as f64).max(0.))
+            .collect();
+        Grid::new(data, res, min, max)
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.data[(z * self.res.1 + y) * self.res.0 + x]
+    }
+
+    pub fn density(&self, pos: Point3) -> f64 {
+        let size = self.max - self.min;
+        let local = pos - self.min;
+        let (nx, ny, nz) = self.res;
+        let fx = local.0 / size.0 * nx as f64 - 0.5;
+        let fy = local.1 / size.1 * ny as f64 - 0.5;
+        let fz = local.2 / size.2 * nz as f64 - 0.5;
+        let outside = |f: f64, n: usize| f < -0.5 || f > n as f64 - 0.5;
+        if outside(fx, nx) || outside(fy, ny) || outside(fz, nz) {
+            return 0.;
+        }
+
+        let split = |f: f64, n: usize| {
+            let f = f.clamp(0., (n - 1) as f64);
+            let i = (f as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), f - i as f64)
+        };
+        let (x0, x1, tx) = split(fx, nx);
+        let (y0, y1, ty) = split(fy, ny);
+        let (z0, z1, tz) = split(fz, nz);
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let c00 = lerp(self.voxel(x0, y0, z0), self.voxel(x1, y0, z0), tx);
+        let c10 = lerp(self.voxel(x0, y1, z0), self.voxel(x1, y1, z0), tx);
+        let c01 = lerp(self.voxel(x0, y0, z1), self.voxel(x1, y0, z1), tx);
+        let c11 = lerp(self.voxel(x0, y1, z1), self.voxel(x1, y1, z1), tx);
+        lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz)
+    }
+
+    fn traverse(
+        &self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        mut f: impl FnMut(f64, f64, f64) -> bool,
+    ) {
+        // walk the majorant cells along the ray (3D DDA), f(t0, t1, majorant) returns false to stop
+        let org = [ray.org.0, ray.org.1, ray.org.2];
+        let dir = [ray.dir.0, ray.dir.1, ray.dir.2];
+        let min = [self.min.0, self.min.1, self.min.2];
+        let max = [self.max.0, self.max.1, self.max.2];
+        let res = [self.maj_res.0, self.maj_res.1, self.maj_res.2];
+        let voxels = [self.res.0, self.res.1, self.res.2];
+
+        let (mut t0, mut t1) = (t_min, t_max);
+        for a in 0..3 {
+            let inv = 1. / dir[a];
+            let (mut ta, mut tb) = ((min[a] - org[a]) * inv, (max[a] - org[a]) * inv);
+            if ta > tb {
+                std::mem::swap(&mut ta, &mut tb);
+            }
+            t0 = t0.max(ta);
+            t1 = t1.min(tb);
+        }
+        if t0 >= t1 {
+            return;
+        }
+
+        let mut cell = [0_i64; 3];
+        let mut step = [0_i64; 3];
+        let mut t_next = [INF; 3];
+        let mut t_delta = [INF; 3];
+        for a in 0..3 {
+            // a cell spans BLOCK voxels, the last one is clipped at max by t1
+            let width = (max[a] - min[a]) * BLOCK as f64 / voxels[a] as f64;
+            let p = org[a] + dir[a] * t0;
+            cell[a] = (((p - min[a]) / width) as i64).clamp(0, res[a] as i64 - 1);
+            if dir[a] > 0. {
+                step[a] = 1;
+                t_next[a] = (min[a] + (cell[a] + 1) as f64 * width - org[a]) / dir[a];
+                t_delta[a] = width / dir[a];
+            } else if dir[a] < 0. {
+                step[a] = -1;
+                t_next[a] = (min[a] + cell[a] as f64 * width - org[a]) / dir[a];
+                t_delta[a] = -width / dir[a];
+            }
+        }
+
+        let mut t = t0;
+        loop {
+            let a = if t_next[0] < t_next[1] && t_next[0] < t_next[2] {
+                0
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            let t_exit = t_next[a].min(t1);
+            let id = (cell[2] as usize * res[1] + cell[1] as usize) * res[0] + cell[0] as usize;
+            if !f(t, t_exit, self.majorants[id]) || t_exit >= t1 {
+                return;
+            }
+
+            t = t_exit;
+            cell[a] += step[a];
+            t_next[a] += t_delta[a];
+            if cell[a] < 0 || cell[a] >= res[a] as i64 {
+                return;
+            }
+        }
+    }
+
+    pub fn sample_distance(
+        &self,
+        sigma_a: &Color,
+        sigma_s: &Color,
+        ray: &Ray,
+        t_max: f64,
+        emission: impl Fn(Point3) -> Color,
+        rand: &mut XorRand,
+    ) -> (Option<f64>, Color, Color) {
+        //return (distance of the first real collision, throughput weight, emitted radiance)
+        // delta tracking against the largest channel, the others are corrected by the weight
+        let sigma_e = &(*sigma_a + *sigma_s);
+        let mut result = None;
+        let mut weight = Vec3::new(1.);
+        let mut emitted = Vec3::new(0.);
+        self.traverse(ray, 0., t_max, |t0, t1, majorant| {
+            let sigma_maj = max_elm(sigma_e) * majorant;
+            if sigma_maj <= 0. {
+                return true;
+            }
+
+            let mut t = t0;
+            loop {
+                t -= (rand.next01()).ln() / sigma_maj;
+                if t >= t1 {
+                    return true;
+                }
+                let pos = ray.org + ray.dir * t;
+                let density = self.density(pos);
+                let sigma_t = *sigma_e * density;
+                let sigma_n = Vec3::new(sigma_maj) - sigma_t;
+
+                // every tentative collision estimates the emission
+                let source = multiply(*sigma_a * density, emission(pos));
+                emitted = emitted + multiply(weight, source) / sigma_maj;
+
+                if rand.next01() * sigma_maj < avg_elm(&sigma_t) {
+                    weight = multiply(weight, *sigma_s * density) / avg_elm(&sigma_t);
+                    result = Some(t);
+                    return false;
+                } else if avg_elm(&sigma_n) > 0. {
+                    weight = multiply(weight, sigma_n) / avg_elm(&sigma_n);
+                }
+            }
+        });
+        (result, weight, emitted)
+    }
+
+    pub fn transmittance(
+        &self,
+        sigma_e: &Color,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        rand: &mut XorRand,
+    ) -> Color {
+        // ratio tracking
+        let mut transmittance = Vec3::new(1.);
+        self.traverse(ray, t_min, t_max, |t0, t1, majorant| {
+            let sigma_maj = max_elm(sigma_e) * majorant;
+            if sigma_maj <= 0. {
+                return true;
+            }
+
+            let mut t = t0;
+            loop {
+                t -= (rand.next01()).ln() / sigma_maj;
+                if t >= t1 {
+                    return true;
+                }
+                let density = self.density(ray.org + ray.dir * t);
+                transmittance = multiply(
+                    transmittance,
+                    Vec3::new(1.) - *sigma_e * (density / sigma_maj),
+                );
+                if max_elm(&transmittance) <= 0. {
+                    return false;
+                }
+            }
+        });
+        clamp_vec(transmittance, 0., 1.)
+    }
+}
+
+fn hash(x: i64, y: i64, z: i64, seed: u32) -> f64 {
+    let mut h = (x as u32).wrapping_mul(73856093)
+        ^ (y as u32).wrapping_mul(19349663)
+        ^ (z as u32).wrapping_mul(83492791)
+        ^ seed.wrapping_mul(2654435761);
+    h ^= h >> 13;
+    h = h.wrapping_mul(1274126177);
+    h ^= h >> 16;
+    h as f64 / u32::MAX as f64
+}
+
+pub fn value_noise(pos: Point3, seed: u32) -> f64 {
+    //return a smooth noise value in [0, 1]
+    let (x0, y0, z0) = (pos.0.floor(), pos.1.floor(), pos.2.floor());
+    let smooth = |t: f64| t * t * (3. - 2. * t);
+    let (tx, ty, tz) = (smooth(pos.0 - x0), smooth(pos.1 - y0), smooth(pos.2 - z0));
+    let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    let corner = |dx: i64, dy: i64, dz: i64| hash(x0 + dx, y0 + dy, z0 + dz, seed);
+    let c00 = lerp(corner(0, 0, 0), corner(1, 0, 0), tx);
+    let c10 = lerp(corner(0, 1, 0), corner(1, 1, 0), tx);
+    let c01 = lerp(corner(0, 0, 1), corner(1, 0, 1), tx);
+    let c11 = lerp(corner(0, 1, 1), corner(1, 1, 1), tx);
+    lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz)
+}
+
+#[allow(unused)]
+pub fn fbm(pos: Point3, octaves: u32, seed: u32) -> f64 {
+    //return fractal noise in [0, 1]
+    let mut sum = 0.;
+    let mut amp = 0.5;
+    let mut freq = 1.;
+    let mut norm = 0.;
+    for i in 0..octaves {
+        sum += value_noise(pos * freq, seed.wrapping_add(i)) * amp;
+        norm += amp;
+        amp *= 0.5;
+        freq *= 2.;
+    }
+    sum / norm
+}